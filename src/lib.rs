@@ -1,51 +1,522 @@
-use console::style;
-use rusqlite::{Connection, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use console::{style, Color, Term};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::{Connection, DatabaseName, Row, Transaction};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
+use std::io::{self, BufRead, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-#[derive(Debug)]
+pub mod migrations;
+pub mod store;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+pub use store::{InMemoryStore, SqliteStore, TodoStore};
+
+// Errors returned by the library
+#[derive(Debug, thiserror::Error)]
+pub enum TodoError {
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error("No task with id {0}")]
+    NotFound(i32),
+    #[error("{0}")]
+    Validation(String),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("Could not determine the user's home directory: {0}")]
+    Home(String),
+    #[error("The database is at schema version {0}, but this todo only knows up to {1}. Upgrade todo to use it.")]
+    SchemaTooNew(u32, u32),
+}
+
+pub type Result<T, E = TodoError> = std::result::Result<T, E>;
+
+// Serializes is_done as a boolean
+mod completed {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(is_done: &u8, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bool(*is_done == 1)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+        Ok(u8::from(bool::deserialize(deserializer)?))
+    }
+}
+
+// In JSON, is_done is a `completed` boolean and date_added an RFC 3339 string
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Todo {
     pub id: i32,
     pub name: String,
-    pub date_added: String, // Using f64 for timestamp
+    pub date_added: DateTime<Utc>,
+    #[serde(rename = "completed", with = "completed")]
     pub is_done: u8,
+    pub parent_id: Option<i32>,
+    pub due_date: Option<String>, // YYYY-MM-DD
+    pub priority: Option<String>, // high, medium or low
+    pub tags: Vec<String>,
+    pub list: Option<String>,  // the named list it's in, if any
+    pub color: Option<String>, // one of COLORS
+}
+
+// Label colors a task can be given with set_color()
+pub const COLORS: [&str; 4] = ["red", "yellow", "green", "blue"];
+
+// How date_added is stored: UTC, in the format of sqlite's current_timestamp
+const STORED_DATETIME: &str = "%Y-%m-%d %H:%M:%S";
+
+// Reads date_added as stored, and also as older versions may have left it:
+// with fractional seconds, as RFC 3339 text or as a julian day number
+struct StoredDateTime(DateTime<Utc>);
+
+impl FromSql for StoredDateTime {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let julian_day = match value {
+            ValueRef::Text(_) => {
+                let text = value.as_str()?;
+                if let Ok(day) = text.parse::<f64>() {
+                    day
+                } else {
+                    let naive = NaiveDateTime::parse_from_str(text, STORED_DATETIME)
+                        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f"));
+                    return match naive {
+                        Ok(naive) => Ok(StoredDateTime(naive.and_utc())),
+                        Err(_) => DateTime::parse_from_rfc3339(text)
+                            .map(|date| StoredDateTime(date.with_timezone(&Utc)))
+                            .map_err(|error| FromSqlError::Other(Box::new(error))),
+                    };
+                }
+            }
+            ValueRef::Real(day) => day,
+            ValueRef::Integer(day) => day as f64,
+            _ => return Err(FromSqlError::InvalidType),
+        };
+        // Julian day 2440587.5 is the unix epoch
+        let millis = ((julian_day - 2440587.5) * 86_400_000.0).round() as i64;
+        DateTime::from_timestamp_millis(millis)
+            .map(StoredDateTime)
+            .ok_or(FromSqlError::OutOfRange(millis))
+    }
+}
+
+// Columns selected when reading todos, in the order expected by from_row()
+const TODO_COLUMNS: &str =
+    "id, name, date_added, is_done, parent_id, due_date, priority, tags, list, color";
+
+// A task written with the `add` syntax, e.g. "Pay rent due:2024-05-01 prio:high +home"
+#[derive(Debug, Default, PartialEq)]
+pub struct ParsedTask {
+    pub name: String,
+    pub due_date: Option<String>,
+    pub priority: Option<String>,
+    pub tags: Vec<String>,
+}
+
+// What a name fragment given instead of an id refers to
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    Unique(i32),
+    Ambiguous(Vec<i32>),
+    None,
+}
+
+// How a list of todos is written out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    // Aligned and colored, for people
+    Table,
+    // One tab separated line per task, for awk and friends
+    Plain,
+    Csv,
+    Json,
+}
+
+// How many tasks are pending and done
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Stats {
+    pub pending: usize,
+    pub done: usize,
+    pub total: usize,
+}
+
+impl Stats {
+    // Share of the tasks that are done, 0.0 when there are none
+    pub fn completion_rate(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.done as f64 / self.total as f64
+        }
+    }
+}
+
+// Which tasks to list, and in what order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListFilter {
+    pub offset: usize,
+    // All the remaining tasks when there's no limit
+    pub limit: Option<usize>,
+    // Only the done (true) or pending (false) tasks
+    pub status: Option<bool>,
+    // Only the tasks whose name contains this text, ignoring case
+    pub search: Option<String>,
+    // Only the tasks in this named list
+    pub list: Option<String>,
+    // Only the tasks added within this range of local dates or datetimes,
+    // both ends being inclusive. A date without a time covers that whole day.
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub sort: ListSort,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ListSort {
+    #[default]
+    Id,
+    // Pending tasks first, then by id
+    Status,
+    // Oldest first
+    Date,
+    // Most recently added first
+    Newest,
+}
+
+impl ListSort {
+    pub fn parse(input: &str) -> Option<ListSort> {
+        match input {
+            "id" => Some(ListSort::Id),
+            "status" => Some(ListSort::Status),
+            "date" => Some(ListSort::Date),
+            "newest" => Some(ListSort::Newest),
+            _ => None,
+        }
+    }
+}
+
+// Marks for done and pending tasks in the table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusGlyphs {
+    // ✓ and ○
+    Unicode,
+    // [x] and [ ], for terminals that mangle Unicode
+    Ascii,
+}
+
+impl StatusGlyphs {
+    fn mark(self, is_done: bool) -> &'static str {
+        match (self, is_done) {
+            (StatusGlyphs::Unicode, true) => "✓",
+            (StatusGlyphs::Unicode, false) => "○",
+            (StatusGlyphs::Ascii, true) => "[x]",
+            (StatusGlyphs::Ascii, false) => "[ ]",
+        }
+    }
+
+    // Terminal columns taken by a mark
+    fn width(self) -> usize {
+        match self {
+            StatusGlyphs::Unicode => 1,
+            StatusGlyphs::Ascii => 3,
+        }
+    }
+}
+
+impl ListFormat {
+    pub fn parse(input: &str) -> Option<ListFormat> {
+        match input {
+            "table" => Some(ListFormat::Table),
+            "plain" => Some(ListFormat::Plain),
+            "csv" => Some(ListFormat::Csv),
+            "json" => Some(ListFormat::Json),
+            _ => None,
+        }
+    }
 }
 
 impl Todo {
     // Constructor for a new Todo instance
-    pub fn new(id: i32, name: String, date_added: String, is_done: u8) -> Self {
+    pub fn new(id: i32, name: String, date_added: DateTime<Utc>, is_done: u8) -> Self {
         Todo {
             id,
             name,
             date_added,
             is_done,
+            parent_id: None,
+            due_date: None,
+            priority: None,
+            tags: Vec::new(),
+            list: None,
+            color: None,
         }
     }
 
-    // Add a new todo to the database
-    pub fn add(conn: &Connection, name: &str) -> Result<()> {
-        conn.execute("INSERT INTO todo (name) VALUES (?)", &[name])?;
-        Ok(())
+    // Builds a Todo from a row selected with TODO_COLUMNS
+    fn from_row(row: &Row) -> rusqlite::Result<Todo> {
+        Ok(Todo {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            date_added: row.get::<_, StoredDateTime>(2)?.0,
+            is_done: row.get(3)?,
+            parent_id: row.get(4)?,
+            due_date: row.get(5)?,
+            priority: row.get(6)?,
+            tags: row
+                .get::<_, Option<String>>(7)?
+                .map(|tags| tags.split(' ').map(String::from).collect())
+                .unwrap_or_default(),
+            list: row.get(8)?,
+            color: row.get(9)?,
+        })
+    }
+
+    // Add a new todo to the database, returning it as stored
+    pub fn add(conn: &Connection, name: &str) -> Result<Todo> {
+        let name = validate_name(name)?;
+        retry_write(conn, || {
+            Ok(conn
+                .prepare_cached("INSERT INTO todo (name) VALUES (?)")?
+                .execute([name])?)
+        })?;
+        Todo::last_inserted(conn)
+    }
+
+    // Adds several todos in one transaction, which is much faster than
+    // calling add for each one. Adds nothing if any of the names is invalid.
+    pub fn add_many(conn: &mut Connection, names: &[&str]) -> Result<Vec<Todo>> {
+        with_transaction(conn, |tx| {
+            names.iter().map(|name| Todo::add(tx, name)).collect()
+        })
+    }
+
+    // Adds tasks parsed with the `add` syntax in one transaction, all of them
+    // or none. `force` allows overly long names, as in add_parsed().
+    pub fn add_all(conn: &mut Connection, tasks: &[ParsedTask], force: bool) -> Result<Vec<Todo>> {
+        with_transaction(conn, |tx| {
+            tasks
+                .iter()
+                .map(|task| Todo::add_parsed(tx, task, None, force))
+                .collect()
+        })
+    }
+
+    // Reads back the row just inserted on this connection, which other
+    // processes adding tasks at the same time can't change
+    fn last_inserted(conn: &Connection) -> Result<Todo> {
+        Todo::get(conn, conn.last_insert_rowid() as i32)
+    }
+
+    // Returns the id of a task with the same name, ignoring case, if there is one
+    pub fn find_duplicate(conn: &Connection, name: &str) -> Result<Option<i32>> {
+        let mut stmt =
+            conn.prepare_cached("SELECT id FROM todo WHERE name = ?1 COLLATE NOCASE LIMIT 1")?;
+        let mut rows = stmt.query([name.trim()])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Adds a new todo unless one with the same name (ignoring case) exists.
+    // Returns the id of the existing task when nothing was added.
+    pub fn add_unique(conn: &Connection, name: &str) -> Result<Option<i32>> {
+        if let Some(id) = Todo::find_duplicate(conn, name)? {
+            return Ok(Some(id));
+        }
+        Todo::add(conn, name)?;
+        Ok(None)
+    }
+
+    // Add a new todo with a given creation date, e.g. when importing old tasks.
+    // Accepts an ISO date or datetime and refuses future dates unless forced.
+    pub fn add_with_date(
+        conn: &Connection,
+        name: &str,
+        date_added: &str,
+        force: bool,
+    ) -> Result<Todo> {
+        let task = ParsedTask {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        Todo::add_parsed(conn, &task, Some(date_added), force)
+    }
+
+    // Add a new todo along with the due date, priority and tags parsed from
+    // the `add` syntax, optionally with a creation date like add_with_date().
+    // `force` allows creation dates in the future and overly long names.
+    pub fn add_parsed(
+        conn: &Connection,
+        task: &ParsedTask,
+        date_added: Option<&str>,
+        force: bool,
+    ) -> Result<Todo> {
+        let name = non_empty_name(&task.name)?;
+        // Read even when forced, so a bad TODO_MAX_NAME_LEN is still reported
+        let max = max_name_len()?;
+        if !force {
+            check_name_len(name, max).map_err(|error| match error {
+                TodoError::Validation(message) => {
+                    TodoError::Validation(format!("{} (use --force to add it anyway)", message))
+                }
+                error => error,
+            })?;
+        }
+        let date_added = date_added.map(parse_datetime).transpose()?;
+        if let Some(date_added) = date_added.filter(|date| !force && *date > Utc::now()) {
+            return Err(TodoError::Validation(format!(
+                "'{}' is in the future (use --force to add it anyway)",
+                date_added
+            )));
+        }
+        let due_date = task.due_date.as_deref().map(parse_date).transpose()?;
+        let priority = match task.priority.as_deref() {
+            Some(priority) => Some(parse_priority(priority).ok_or_else(|| {
+                TodoError::Validation(format!("'{}' is not a valid priority", priority))
+            })?),
+            None => None,
+        };
+
+        let date_added = date_added.map(|date| date.format(STORED_DATETIME).to_string());
+        let due_date = due_date.map(|date| date.format("%Y-%m-%d").to_string());
+        let tags = join_tags(&task.tags);
+        retry_write(conn, || {
+            Ok(conn
+                .prepare_cached(
+                    "INSERT INTO todo (name, date_added, due_date, priority, tags)
+                        VALUES (?1, COALESCE(?2, current_timestamp), ?3, ?4, ?5)",
+                )?
+                .execute((name, &date_added, &due_date, priority, &tags))?)
+        })?;
+        Todo::last_inserted(conn)
+    }
+
+    // Add a new todo as a child of an existing one
+    pub fn add_subtask(conn: &Connection, parent_id: i32, name: &str) -> Result<Todo> {
+        let name = validate_name(name)?;
+        // Fails with NotFound rather than the foreign key error
+        Todo::get(conn, parent_id)?;
+        retry_write(conn, || {
+            Ok(conn.execute(
+                "INSERT INTO todo (name, parent_id) VALUES (?1, ?2)",
+                (name, parent_id),
+            )?)
+        })?;
+        Todo::last_inserted(conn)
+    }
+
+    // Checks if making `new_parent_id` the parent of `id` would create a cycle,
+    // which is the case when `id` is the new parent itself or one of its ancestors
+    pub fn would_cycle(conn: &Connection, id: i32, new_parent_id: i32) -> Result<bool> {
+        let mut stmt = conn.prepare(
+            "WITH RECURSIVE ancestors(id) AS (
+                SELECT ?1
+                UNION
+                SELECT todo.parent_id FROM todo
+                    JOIN ancestors ON todo.id = ancestors.id
+                    WHERE todo.parent_id IS NOT NULL
+            )
+            SELECT COUNT(*) FROM ancestors WHERE id = ?2",
+        )?;
+        let count: i32 = stmt.query_row([new_parent_id, id], |row| row.get(0))?;
+        Ok(count > 0)
     }
 
     // List all todo entries in the database
     pub fn list(conn: &Connection, sort_by_status: bool) -> Result<Vec<Todo>> {
-        let sql = if sort_by_status {
-            "SELECT * FROM todo ORDER BY is_done, id"
-        } else {
-            "SELECT * FROM todo ORDER BY id"
+        Todo::list_paginated(conn, 0, None, sort_by_status)
+    }
+
+    // List a window of todo entries, skipping `offset` rows and returning at
+    // most `limit` of them (all remaining rows when there's no limit)
+    pub fn list_paginated(
+        conn: &Connection,
+        offset: usize,
+        limit: Option<usize>,
+        sort_by_status: bool,
+    ) -> Result<Vec<Todo>> {
+        Todo::list_page(
+            conn,
+            &ListFilter {
+                offset,
+                limit,
+                sort: if sort_by_status {
+                    ListSort::Status
+                } else {
+                    ListSort::Id
+                },
+                ..ListFilter::default()
+            },
+        )
+    }
+
+    // List the todos with the given status (true for done, false for pending),
+    // or all of them when there's no status
+    pub fn list_filtered(conn: &Connection, status: Option<bool>) -> Result<Vec<Todo>> {
+        Todo::list_page(
+            conn,
+            &ListFilter {
+                status,
+                ..ListFilter::default()
+            },
+        )
+    }
+
+    // List the todos matching a filter. The filtering, sorting and paging all
+    // happen in sqlite, so only the requested rows are read.
+    pub fn list_page(conn: &Connection, filter: &ListFilter) -> Result<Vec<Todo>> {
+        let order = match filter.sort {
+            ListSort::Id => "id",
+            ListSort::Status => "is_done, id",
+            ListSort::Date => "date_added, id",
+            ListSort::Newest => "date_added DESC, id DESC",
         };
-        let mut stmt = conn.prepare(sql)?;
-        let todo_iter = stmt.query_map((), |row| {
-            Ok(Todo::new(
-                row.get(0)?,
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-            ))
-        })?;
+        let sql = format!(
+            "SELECT {} FROM todo
+                WHERE (?1 IS NULL OR is_done = ?1)
+                    AND (?2 IS NULL OR name LIKE '%' || ?2 || '%' ESCAPE '\\')
+                    AND (?5 IS NULL OR list = ?5)
+                    AND (?6 IS NULL OR date_added >= ?6)
+                    AND (?7 IS NULL OR date_added <= ?7)
+                ORDER BY {} LIMIT ?3 OFFSET ?4",
+            TODO_COLUMNS, order
+        );
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let search = filter.search.as_deref().map(escape_like);
+        let since = filter.since.as_deref().map(parse_datetime).transpose()?;
+        let until = filter
+            .until
+            .as_deref()
+            .map(|until| match NaiveDate::parse_from_str(until, "%Y-%m-%d") {
+                Ok(date) => local_to_utc(
+                    date.and_time(NaiveTime::MIN) + chrono::Duration::seconds(86399),
+                    until,
+                ),
+                Err(_) => parse_datetime(until),
+            })
+            .transpose()?;
+        let format = |datetime: DateTime<Utc>| datetime.format(STORED_DATETIME).to_string();
+        // A negative LIMIT means no limit in sqlite
+        let limit = filter.limit.map_or(-1, |limit| limit as i64);
+        let todo_iter = stmt.query_map(
+            (
+                filter.status.map(u8::from),
+                search,
+                limit,
+                filter.offset as i64,
+                filter.list.as_deref(),
+                since.map(format),
+                until.map(format),
+            ),
+            Todo::from_row,
+        )?;
 
         let mut todos = Vec::new();
         for todo in todo_iter {
@@ -54,307 +525,3215 @@ impl Todo {
         Ok(todos)
     }
 
-    // Toggle the 'is_done' property of a Todo
-    pub fn toggle(conn: &Connection, id: i32) -> Result<()> {
-        conn.execute("UPDATE todo SET is_done = 1 - is_done WHERE id = ?", &[&id])?;
-        Ok(())
+    // List the todos added within a date range, both ends being inclusive.
+    // A date without a time covers that whole day.
+    pub fn list_between(
+        conn: &Connection,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> Result<Vec<Todo>> {
+        Todo::list_page(
+            conn,
+            &ListFilter {
+                since: since.map(str::to_string),
+                until: until.map(str::to_string),
+                ..ListFilter::default()
+            },
+        )
+    }
+
+    // List the `n` most recently added todos, newest first
+    pub fn latest(conn: &Connection, n: usize) -> Result<Vec<Todo>> {
+        Todo::list_page(
+            conn,
+            &ListFilter {
+                limit: Some(n),
+                sort: ListSort::Newest,
+                ..ListFilter::default()
+            },
+        )
+    }
+
+    // Runs sqlite's integrity check over the whole database, returning
+    // whether it found it healthy
+    pub fn integrity_check(conn: &Connection) -> Result<bool> {
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let results = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(results == ["ok"])
+    }
+
+    // Counts the pending and done tasks in one query
+    pub fn stats(conn: &Connection) -> Result<Stats> {
+        let (total, done): (usize, usize) = conn
+            .prepare_cached("SELECT COUNT(*), COALESCE(SUM(is_done), 0) FROM todo")?
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(Stats {
+            pending: total - done,
+            done,
+            total,
+        })
+    }
+
+    // Fetch a single todo by its id
+    pub fn get(conn: &Connection, id: i32) -> Result<Todo> {
+        let sql = format!("SELECT {} FROM todo WHERE id = ?", TODO_COLUMNS);
+        conn.prepare_cached(&sql)?
+            .query_row([id], Todo::from_row)
+            .map_err(not_found(id))
+    }
+
+    // Finds the task whose name starts with `needle`, ignoring case. A task
+    // named exactly `needle` wins over others that only start with it.
+    pub fn resolve(conn: &Connection, needle: &str) -> Result<Resolution> {
+        let needle = needle.trim();
+        if needle.is_empty() {
+            return Ok(Resolution::None);
+        }
+        let pattern = escape_like(needle);
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, name FROM todo WHERE name LIKE ?1 || '%' ESCAPE '\\' ORDER BY id",
+        )?;
+        let matches = stmt
+            .query_map([pattern], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i32, String)>>>()?;
+
+        let exact: Vec<i32> = matches
+            .iter()
+            .filter(|(_, name)| name.to_lowercase() == needle.to_lowercase())
+            .map(|(id, _)| *id)
+            .collect();
+        Ok(match (exact.as_slice(), matches.as_slice()) {
+            ([id], _) => Resolution::Unique(*id),
+            (_, []) => Resolution::None,
+            (_, [(id, _)]) => Resolution::Unique(*id),
+            (_, _) => Resolution::Ambiguous(matches.iter().map(|(id, _)| *id).collect()),
+        })
+    }
+
+    // Toggle the 'is_done' property of a Todo, returning the new status
+    // (true for done)
+    pub fn toggle(conn: &Connection, id: i32) -> Result<bool> {
+        retry_write(conn, || {
+            conn.prepare_cached(
+                "UPDATE todo SET is_done = 1 - is_done WHERE id = ? RETURNING is_done",
+            )?
+            .query_row([id], |row| row.get::<_, u8>(0))
+            .map(|is_done| is_done == 1)
+            .map_err(not_found(id))
+        })
+    }
+
+    // Changes the name of a task
+    pub fn rename(conn: &Connection, id: i32, name: &str) -> Result<()> {
+        let name = validate_name(name)?;
+        let changed = retry_write(conn, || {
+            Ok(conn.execute("UPDATE todo SET name = ?1 WHERE id = ?2", (name, id))?)
+        })?;
+        expect_changed(changed, id)
+    }
+
+    // Sets the date (YYYY-MM-DD) a task is due
+    pub fn set_due(conn: &Connection, id: i32, due_date: &str) -> Result<()> {
+        let due_date = parse_date(due_date)?;
+        let due_date = due_date.format("%Y-%m-%d").to_string();
+        let changed = retry_write(conn, || {
+            Ok(conn.execute(
+                "UPDATE todo SET due_date = ?1 WHERE id = ?2",
+                (&due_date, id),
+            )?)
+        })?;
+        expect_changed(changed, id)
+    }
+
+    // Moves a task to a named list. Lists are just this value on their tasks,
+    // so moving a task to a new name creates that list.
+    pub fn move_to_list(conn: &Connection, id: i32, list: &str) -> Result<()> {
+        let list = list.trim();
+        if list.is_empty() {
+            return Err(TodoError::Validation(
+                "A list name can't be empty".to_string(),
+            ));
+        }
+        let changed = retry_write(conn, || {
+            Ok(conn.execute("UPDATE todo SET list = ?1 WHERE id = ?2", (list, id))?)
+        })?;
+        expect_changed(changed, id)
+    }
+
+    // Gives a task one of the COLORS as a label, or removes its label
+    pub fn set_color(conn: &Connection, id: i32, color: Option<&str>) -> Result<()> {
+        let color = match color.map(str::to_lowercase) {
+            Some(color) if !COLORS.contains(&color.as_str()) => {
+                return Err(TodoError::Validation(format!(
+                    "'{}' is not a color, expected one of {}",
+                    color,
+                    COLORS.join(", ")
+                )))
+            }
+            color => color,
+        };
+        let changed = retry_write(conn, || {
+            Ok(conn.execute("UPDATE todo SET color = ?1 WHERE id = ?2", (&color, id))?)
+        })?;
+        expect_changed(changed, id)
+    }
+
+    // Pushes a task's due date back by some days, counting from today when it
+    // had none. Returns the new due date.
+    pub fn snooze(conn: &Connection, id: i32, days: i64) -> Result<NaiveDate> {
+        let todo = Todo::get(conn, id)?;
+        let from = match todo.due_date.as_deref() {
+            Some(due_date) => parse_date(due_date)?,
+            None => Local::now().date_naive(),
+        };
+        let due_date = chrono::Duration::try_days(days)
+            .and_then(|days| from.checked_add_signed(days))
+            .ok_or_else(|| TodoError::Validation(format!("Can't snooze by {} days", days)))?;
+        Todo::set_due(conn, id, &due_date.format("%Y-%m-%d").to_string())?;
+        Ok(due_date)
+    }
+
+    // Exports all tasks as an iCalendar (RFC 5545) document with one VTODO each
+    pub fn export_ical(conn: &Connection) -> Result<String> {
+        // When the document was made, which is every VTODO's DTSTAMP since
+        // they're not sent through a scheduling method
+        let stamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_string(),
+            "VERSION:2.0".to_string(),
+            "PRODID:-//rust-todo-list//todo//EN".to_string(),
+        ];
+        for todo in Todo::list(conn, false)? {
+            lines.push("BEGIN:VTODO".to_string());
+            lines.push(format!("UID:todo-{}@rust-todo-list", todo.id));
+            lines.push(format!("DTSTAMP:{}", stamp));
+            lines.push(format!(
+                "CREATED:{}",
+                todo.date_added.format("%Y%m%dT%H%M%SZ")
+            ));
+            lines.push(format!("SUMMARY:{}", escape_ical_text(&todo.name)));
+            if let Some(due) = todo
+                .due_date
+                .as_deref()
+                .and_then(|due| parse_date(due).ok())
+            {
+                lines.push(format!("DUE;VALUE=DATE:{}", due.format("%Y%m%d")));
+            }
+            let status = if todo.is_done == 1 {
+                "COMPLETED"
+            } else {
+                "NEEDS-ACTION"
+            };
+            lines.push(format!("STATUS:{}", status));
+            lines.push("END:VTODO".to_string());
+        }
+        lines.push("END:VCALENDAR".to_string());
+
+        Ok(lines.iter().map(|line| fold_ical_line(line)).collect())
+    }
+
+    // Exports all tasks in the todo.txt format, one line per task.
+    // Done tasks get the "x " prefix, priorities become (A) to (C), tags
+    // become +projects and due dates "due:" tags. Since completion dates aren't
+    // tracked, and todo.txt only allows a creation date after one, done tasks
+    // are written without dates. @contexts are kept as part of the name.
+    pub fn export_todotxt(conn: &Connection) -> Result<String> {
+        let mut output = String::new();
+        for todo in Todo::list(conn, false)? {
+            let mut line = String::new();
+            if todo.is_done == 1 {
+                line.push_str("x ");
+            }
+            let priority = match todo.priority.as_deref() {
+                Some("high") => Some("(A)"),
+                Some("medium") => Some("(B)"),
+                Some("low") => Some("(C)"),
+                _ => None,
+            };
+            // Priorities other than (A) to (C) are imported as part of the name
+            let (name_priority, name) = split_priority(&todo.name);
+            if let Some(priority) = priority.or(name_priority) {
+                line.push_str(priority);
+                line.push(' ');
+            }
+            if todo.is_done == 0 {
+                line.push_str(&todo.date_added.format("%Y-%m-%d ").to_string());
+            }
+            line.push_str(name);
+            for tag in &todo.tags {
+                line.push_str(" +");
+                line.push_str(tag);
+            }
+            if let Some(due_date) = &todo.due_date {
+                line.push_str(" due:");
+                line.push_str(due_date);
+            }
+            output.push_str(&line);
+            output.push('\n');
+        }
+        Ok(output)
+    }
+
+    // Imports tasks written in the todo.txt format, returning how many were added.
+    // Creation dates, priorities (A) to (C), +projects and "due:" tags are mapped
+    // to their columns, everything else (other priorities, @contexts, other
+    // key:value tags) stays in the name.
+    pub fn import_todotxt(conn: &Connection, input: &str) -> Result<usize> {
+        let tx = conn.unchecked_transaction()?;
+        let mut count = 0;
+        for (index, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (is_done, mut rest) = match line.strip_prefix("x ") {
+                Some(rest) => (1, rest),
+                None => (0, line),
+            };
+            let (priority, after_priority) = split_priority(rest);
+            rest = after_priority;
+
+            // Done tasks may have a completion date, then a creation date
+            let mut dates = Vec::new();
+            while let Some((word, tail)) = rest.split_once(' ') {
+                match parse_date(word) {
+                    Ok(date) if dates.len() < 2 => {
+                        dates.push(date);
+                        rest = tail;
+                    }
+                    _ => break,
+                }
+            }
+            let created = if is_done == 1 && dates.len() < 2 {
+                None
+            } else {
+                dates.last()
+            };
+
+            let mut task = parse_task_input(rest);
+            match priority {
+                Some("(A)") => task.priority = Some("high".to_string()),
+                Some("(B)") => task.priority = Some("medium".to_string()),
+                Some("(C)") => task.priority = Some("low".to_string()),
+                Some(other) => task.name = format!("{} {}", other, task.name),
+                None => {}
+            }
+            // Dropping the transaction on a bad line rolls back the whole import
+            let name = validate_name(&task.name).map_err(|error| match error {
+                TodoError::Validation(message) => {
+                    TodoError::Validation(format!("Line {}: {}", index + 1, message))
+                }
+                error => error,
+            })?;
+
+            tx.execute(
+                "INSERT INTO todo (name, date_added, is_done, due_date, priority, tags)
+                    VALUES (?1, COALESCE(?2, current_timestamp), ?3, ?4, ?5, ?6)",
+                (
+                    name,
+                    created.map(|date| date.format("%Y-%m-%d 00:00:00").to_string()),
+                    is_done,
+                    &task.due_date,
+                    &task.priority,
+                    join_tags(&task.tags),
+                ),
+            )?;
+            count += 1;
+        }
+        tx.commit()?;
+        Ok(count)
+    }
+
+    // Removes every completed task, returning how many were deleted
+    pub fn clear_completed(conn: &Connection) -> Result<usize> {
+        retry_write(conn, || {
+            Ok(conn.execute("DELETE FROM todo WHERE is_done = 1", ())?)
+        })
     }
 
     // Reset the database, clearing all entries
     pub fn reset(conn: &Connection) -> Result<()> {
-        conn.execute("DELETE FROM todo", ())?;
+        retry_write(conn, || Ok(conn.execute("DELETE FROM todo", ())?))?;
         Ok(())
     }
 
-    // Removes a task
+    // Removes a task (and its subtasks, through ON DELETE CASCADE)
     pub fn rm(conn: &Connection, id: i32) -> Result<()> {
-        conn.execute("DELETE FROM todo WHERE id = ?", &[&id])?;
-        Ok(())
+        let changed = retry_write(conn, || {
+            Ok(conn
+                .prepare_cached("DELETE FROM todo WHERE id = ?")?
+                .execute([id])?)
+        })?;
+        expect_changed(changed, id)
+    }
+
+    // Lists a task along with all its subtasks, i.e. what rm would delete
+    pub fn subtree(conn: &Connection, id: i32) -> Result<Vec<Todo>> {
+        let sql = format!(
+            "WITH RECURSIVE descendants(id) AS (
+                SELECT id FROM todo WHERE id = ?1
+                UNION
+                SELECT todo.id FROM todo
+                    JOIN descendants ON todo.parent_id = descendants.id
+            )
+            SELECT {} FROM todo WHERE id IN descendants ORDER BY id",
+            TODO_COLUMNS
+        );
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let todo_iter = stmt.query_map([id], Todo::from_row)?;
+
+        let mut todos = Vec::new();
+        for todo in todo_iter {
+            todos.push(todo?);
+        }
+        if todos.is_empty() {
+            return Err(TodoError::NotFound(id));
+        }
+        Ok(todos)
     }
 
-    // Prints a list of todos objects
-    pub fn print_list(todos: Vec<Todo>) -> Result<()> {
+    // Reorders todos so that each child comes right after its parent.
+    // Todos whose parent is not in the list are treated as top level tasks.
+    pub fn tree_order(todos: Vec<Todo>) -> Vec<Todo> {
+        let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+        let mut children: HashMap<Option<i32>, Vec<Todo>> = HashMap::new();
         for todo in todos {
-            // Styles the string representing the status
-            let status = if todo.is_done == 1 {
-                style("Done").green()
-            } else {
-                style("Pending").red()
-            };
-            println!(
-                "{:>4} | {:<44} {:<8} {}",
-                style(todo.id).cyan().bright(),
-                style(truncate_at(&todo.name, 44)).bright(),
-                status,
-                style(todo.date_added).dim(),
-            );
+            let parent = todo.parent_id.filter(|parent_id| ids.contains(parent_id));
+            children.entry(parent).or_default().push(todo);
+        }
+
+        let mut ordered = Vec::new();
+        let mut stack: Vec<Todo> = children.remove(&None).unwrap_or_default();
+        stack.reverse();
+        while let Some(todo) = stack.pop() {
+            if let Some(mut kids) = children.remove(&Some(todo.id)) {
+                kids.reverse();
+                stack.extend(kids);
+            }
+            ordered.push(todo);
         }
+        ordered
+    }
+
+    // Prints a list of todos objects, indenting subtasks under their parent
+    pub fn print_list(todos: Vec<Todo>, glyphs: StatusGlyphs) -> Result<()> {
+        print!("{}", Todo::render(todos, ListFormat::Table, glyphs));
         Ok(())
     }
-}
 
-// Truncates an str and adds ellipsis if needed
-pub fn truncate_at(input: &str, max: i32) -> String {
-    let max_len: usize = max as usize;
-    if input.len() > max_len {
-        let truncated = &input[..(max_len - 3)];
-        return format!("{}...", truncated);
-    };
+    // Writes out a list of todos in the given format. The glyphs are only
+    // used by the table.
+    pub fn render(todos: Vec<Todo>, format: ListFormat, glyphs: StatusGlyphs) -> String {
+        match format {
+            ListFormat::Table => {
+                let terminal_width = Term::stdout()
+                    .size_checked()
+                    .map(|(_rows, columns)| columns as usize);
+                render_table(todos, name_column_width(terminal_width, glyphs), glyphs)
+            }
+            ListFormat::Plain => render_plain(&todos),
+            ListFormat::Csv => render_csv(&todos),
+            ListFormat::Json => render_json(&todos),
+        }
+    }
+
+    // Exports all tasks as CSV, with a header row
+    pub fn export_csv(conn: &Connection) -> Result<String> {
+        Ok(render_csv(&Todo::list(conn, false)?))
+    }
+
+    // Exports all tasks as a JSON array
+    pub fn export_json(conn: &Connection) -> Result<String> {
+        Ok(render_json(&Todo::list(conn, false)?))
+    }
+}
+
+// Renders the colored table, indenting subtasks under their parent
+fn render_table(todos: Vec<Todo>, name_width: usize, glyphs: StatusGlyphs) -> String {
+    let mut output = String::new();
+    let parents: HashMap<i32, Option<i32>> =
+        todos.iter().map(|todo| (todo.id, todo.parent_id)).collect();
+    for todo in todos {
+        // Counts how many ancestors of this task are also being printed
+        let mut depth = 0;
+        let mut parent = todo.parent_id;
+        while let Some(parent_id) = parent {
+            match parents.get(&parent_id) {
+                Some(grandparent) if depth < parents.len() => {
+                    depth += 1;
+                    parent = *grandparent;
+                }
+                _ => break,
+            }
+        }
+        let indent = "  ".repeat(depth);
+        let width = name_width.saturating_sub(indent.len()).max(8);
+        let name = indent + &truncate_at(&todo.name, width);
+        // Pads by display width, as format! would count wide characters as one
+        let padding = " ".repeat(name_width.saturating_sub(name.width()));
+
+        // Styles the mark representing the status
+        let mark = glyphs.mark(todo.is_done == 1);
+        let status = if todo.is_done == 1 {
+            style(mark).green()
+        } else {
+            style(mark).red()
+        };
+        // A color label tints the id and the bar after it
+        let (id, bar) = match label_color(todo.color.as_deref()) {
+            Some(color) => (style(todo.id).fg(color), style("|").fg(color)),
+            None => (style(todo.id).cyan(), style("|")),
+        };
+        output.push_str(&format!(
+            "{:>4} {} {}{} {} {}\n",
+            id.bright(),
+            bar.bright(),
+            style(name).bright(),
+            padding,
+            status,
+            style(
+                todo.date_added
+                    .with_timezone(&Local)
+                    .format(STORED_DATETIME)
+            )
+            .dim(),
+        ));
+        // Due date, priority and tags go on a second line when set
+        let mut details = Vec::new();
+        if let Some(due_date) = &todo.due_date {
+            details.push(style(format!("due {}", due_date)).yellow().to_string());
+        }
+        if let Some(priority) = &todo.priority {
+            details.push(style(format!("prio {}", priority)).magenta().to_string());
+        }
+        for tag in &todo.tags {
+            details.push(style(format!("+{}", tag)).blue().to_string());
+        }
+        if !details.is_empty() {
+            output.push_str(&format!("{:>4}   {}\n", "", details.join(" ")));
+        }
+    }
+    output
+}
+
+fn label_color(color: Option<&str>) -> Option<Color> {
+    match color? {
+        "red" => Some(Color::Red),
+        "yellow" => Some(Color::Yellow),
+        "green" => Some(Color::Green),
+        "blue" => Some(Color::Blue),
+        _ => None,
+    }
+}
+
+// Columns written by the plain, CSV and JSON formats
+const RENDERED_COLUMNS: [&str; 10] = [
+    "id",
+    "name",
+    "date_added",
+    "is_done",
+    "parent_id",
+    "due_date",
+    "priority",
+    "tags",
+    "list",
+    "color",
+];
+
+// A todo's values in RENDERED_COLUMNS order, empty when not set
+fn rendered_values(todo: &Todo) -> [String; 10] {
+    [
+        todo.id.to_string(),
+        todo.name.clone(),
+        todo.date_added.format(STORED_DATETIME).to_string(),
+        todo.is_done.to_string(),
+        todo.parent_id.map(|id| id.to_string()).unwrap_or_default(),
+        todo.due_date.clone().unwrap_or_default(),
+        todo.priority.clone().unwrap_or_default(),
+        todo.tags.join(" "),
+        todo.list.clone().unwrap_or_default(),
+        todo.color.clone().unwrap_or_default(),
+    ]
+}
+
+// One line per task without a header, tabs in names turned into spaces
+fn render_plain(todos: &[Todo]) -> String {
+    let mut output = String::new();
+    for todo in todos {
+        let values = rendered_values(todo).map(|value| value.replace(['\t', '\n'], " "));
+        output.push_str(&values.join("\t"));
+        output.push('\n');
+    }
+    output
+}
+
+// CSV as in RFC 4180, quoting only the fields that need it
+fn render_csv(todos: &[Todo]) -> String {
+    let mut output = RENDERED_COLUMNS.join(",");
+    output.push('\n');
+    for todo in todos {
+        let values = rendered_values(todo).map(|value| {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value
+            }
+        });
+        output.push_str(&values.join(","));
+        output.push('\n');
+    }
+    output
+}
+
+// A JSON array with one object per task, as serde writes a Todo
+fn render_json(todos: &[Todo]) -> String {
+    // A Todo has no maps or other values JSON can't represent, so this can't fail
+    serde_json::to_string(todos).expect("Could not serialize the tasks") + "\n"
+}
+
+// Width of everything in a print_list row except the name and status mark:
+// id (4), " | " (3), the space before the mark (1) and space + date (20)
+const ROW_WIDTH_WITHOUT_NAME: usize = 28;
+
+// Works out how wide the name column can be for a terminal of the given width,
+// using 80 columns when the width is unknown (e.g. output isn't a TTY)
+pub fn name_column_width(terminal_width: Option<usize>, glyphs: StatusGlyphs) -> usize {
+    terminal_width
+        .unwrap_or(80)
+        .saturating_sub(ROW_WIDTH_WITHOUT_NAME + glyphs.width())
+        .max(10)
+}
+
+// Truncates an str to fit in max terminal cells, ending it with an ellipsis
+// when something was cut. Wide characters (e.g. CJK) take two cells and
+// combining marks none, so the cut never splits a character.
+pub fn truncate_at(input: &str, max: usize) -> String {
+    if input.width() <= max {
+        return input.to_string();
+    }
+    if max == 0 {
+        return String::new();
+    }
+
+    // Keeps one cell for the ellipsis
+    let mut truncated = String::new();
+    let mut width = 0;
+    for c in input.chars() {
+        width += c.width().unwrap_or(0);
+        if width > max - 1 {
+            break;
+        }
+        truncated.push(c);
+    }
+    truncated.push('…');
+    truncated
+}
+
+// Turns a query that returned no rows for a task into a NotFound error
+fn not_found(id: i32) -> impl FnOnce(rusqlite::Error) -> TodoError {
+    move |error| match error {
+        rusqlite::Error::QueryReturnedNoRows => TodoError::NotFound(id),
+        error => error.into(),
+    }
+}
+
+// Turns an update that touched no rows into a NotFound error,
+// so callers can tell that the task they targeted doesn't exist
+fn expect_changed(changed: usize, id: i32) -> Result<()> {
+    if changed == 0 {
+        return Err(TodoError::NotFound(id));
+    }
+    Ok(())
+}
+
+// Escapes the LIKE wildcards in `input`, for use with ESCAPE '\'
+fn escape_like(input: &str) -> String {
+    input
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Longest task name accepted by default, in characters
+pub const MAX_NAME_LEN: usize = 500;
+
+// Longest task name accepted, MAX_NAME_LEN unless TODO_MAX_NAME_LEN says otherwise
+pub fn max_name_len() -> Result<usize> {
+    parse_max_name_len(env::var("TODO_MAX_NAME_LEN").ok().as_deref())
+}
+
+fn parse_max_name_len(value: Option<&str>) -> Result<usize> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(MAX_NAME_LEN),
+        Some(value) => value.parse().ok().filter(|max| *max > 0).ok_or_else(|| {
+            TodoError::Validation(format!(
+                "TODO_MAX_NAME_LEN must be a positive number, not '{}'",
+                value
+            ))
+        }),
+    }
+}
+
+// Trims a task name, refusing names that are empty, only whitespace or too long
+fn validate_name(name: &str) -> Result<&str> {
+    let name = non_empty_name(name)?;
+    check_name_len(name, max_name_len()?)?;
+    Ok(name)
+}
+
+// Refuses names longer than `max` characters
+fn check_name_len(name: &str, max: usize) -> Result<()> {
+    if name.chars().count() > max {
+        return Err(TodoError::Validation(format!(
+            "A task name can't be longer than {} characters, this one has {}",
+            max,
+            name.chars().count()
+        )));
+    }
+    Ok(())
+}
+
+// Trims a task name, refusing names that are empty or only whitespace
+fn non_empty_name(name: &str) -> Result<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(TodoError::Validation(
+            "A task name can't be empty".to_string(),
+        ));
+    }
+    Ok(name)
+}
+
+// Parses a YYYY-MM-DD date, failing with a message that names the bad input
+fn parse_date(input: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .map_err(|_| TodoError::Validation(format!("'{}' is not a valid date (YYYY-MM-DD)", input)))
+}
+
+// Parses an ISO date or datetime in local time, using midnight when only a
+// date is given, and converts it to UTC like the stored dates
+fn parse_datetime(input: &str) -> Result<DateTime<Utc>> {
+    local_to_utc(parse_naive_datetime(input)?, input)
+}
+
+// Reads a date and time on the local clock as UTC. Times skipped by a
+// daylight saving change don't exist, and repeated ones use the first.
+fn local_to_utc(datetime: NaiveDateTime, input: &str) -> Result<DateTime<Utc>> {
+    Local
+        .from_local_datetime(&datetime)
+        .earliest()
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .ok_or_else(|| {
+            TodoError::Validation(format!("'{}' doesn't exist in the local time zone", input))
+        })
+}
+
+fn parse_naive_datetime(input: &str) -> Result<NaiveDateTime> {
+    for format in [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(input, format) {
+            return Ok(datetime);
+        }
+    }
+    NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .map(|date| date.and_time(NaiveTime::MIN))
+        .map_err(|_| {
+            TodoError::Validation(format!("'{}' is not a valid ISO date or datetime", input))
+        })
+}
+
+// Parses one task per line with the `add` syntax, skipping blank lines and
+// comments starting with '#'
+pub fn parse_task_lines(input: &str) -> Vec<ParsedTask> {
+    input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_task_input)
+        .collect()
+}
+
+// Drops the tasks whose name, ignoring case, came earlier in the list
+pub fn dedupe_tasks(tasks: Vec<ParsedTask>) -> Vec<ParsedTask> {
+    let mut seen = HashSet::new();
+    tasks
+        .into_iter()
+        .filter(|task| seen.insert(task.name.to_lowercase()))
+        .collect()
+}
+
+// Parses the `add` syntax: "due:YYYY-MM-DD", "prio:high|medium|low" and
+// "+tag" tokens can appear anywhere and the remaining words form the name.
+// Tokens with an invalid value stay in the name, and a leading backslash or
+// an escaped colon ("\:") keeps a token from being interpreted.
+pub fn parse_task_input(raw: &str) -> ParsedTask {
+    let mut task = ParsedTask::default();
+    let mut words = Vec::new();
+    for token in raw.split_whitespace() {
+        if let Some(literal) = token.strip_prefix('\\') {
+            words.push(literal.replace("\\:", ":"));
+        } else if token.contains("\\:") {
+            words.push(token.replace("\\:", ":"));
+        } else if let Some(due) = token
+            .strip_prefix("due:")
+            .and_then(|due| parse_date(due).ok())
+        {
+            task.due_date = Some(due.format("%Y-%m-%d").to_string());
+        } else if let Some(priority) = token.strip_prefix("prio:").and_then(parse_priority) {
+            task.priority = Some(priority.to_string());
+        } else if let Some(tag) = token.strip_prefix('+').filter(|tag| !tag.is_empty()) {
+            task.tags.push(tag.to_string());
+        } else {
+            words.push(token.to_string());
+        }
+    }
+    task.name = words.join(" ");
+    task
+}
+
+// Normalizes a priority name, accepting its first letter as a shorthand
+fn parse_priority(input: &str) -> Option<&'static str> {
+    match input.to_lowercase().as_str() {
+        "high" | "h" => Some("high"),
+        "medium" | "med" | "m" => Some("medium"),
+        "low" | "l" => Some("low"),
+        _ => None,
+    }
+}
+
+// Tags are stored space separated, or NULL when there are none
+fn join_tags(tags: &[String]) -> Option<String> {
+    if tags.is_empty() {
+        None
+    } else {
+        Some(tags.join(" "))
+    }
+}
+
+// Splits a leading todo.txt priority such as "(A)" from the rest of a task
+fn split_priority(input: &str) -> (Option<&str>, &str) {
+    let bytes = input.as_bytes();
+    if bytes.len() > 3
+        && bytes[0] == b'('
+        && bytes[1].is_ascii_uppercase()
+        && bytes[2] == b')'
+        && bytes[3] == b' '
+    {
+        (Some(&input[..3]), &input[4..])
+    } else {
+        (None, input)
+    }
+}
+
+// Escapes commas, semicolons, backslashes and newlines in iCalendar TEXT values.
+// CRLF and lone CR line breaks become newlines too, since a raw CR would end
+// the content line.
+fn escape_ical_text(input: &str) -> String {
+    input
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', "\\n")
+}
+
+// Folds an iCalendar content line so no physical line exceeds 75 octets,
+// continuation lines start with a single space, and every line ends in CRLF
+fn fold_ical_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut line_len = 0;
+    for c in line.chars() {
+        if line_len + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            line_len = 1;
+        }
+        folded.push(c);
+        line_len += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+// Database path that stands for an in-memory database, as in sqlite itself
+pub const MEMORY_DB_PATH: &str = ":memory:";
+
+// Returns where the database lives: the given override if any, then the
+// TODO_DB_PATH environment variable, then todo.sqlite in the platform's data
+// folder (see data_dir). A database in ~/todo_db, where older versions kept
+// it, is moved to the data folder the first time. Environment variables are
+// read with `var`, which is env_var outside of tests.
+pub fn resolve_db_path(
+    db_override: Option<&str>,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<PathBuf> {
+    if let Some(path) = db_override {
+        return Ok(PathBuf::from(path));
+    }
+    if let Some(path) = var("TODO_DB_PATH") {
+        return Ok(PathBuf::from(path));
+    }
+    let db_path = data_dir(env::consts::OS, &var)?.join("todo.sqlite");
+    // The data folder can be found without a home folder (e.g. from
+    // XDG_STATE_HOME), and then there's no old database to look for
+    if let Ok(home) = home_dir(env::consts::OS, &var) {
+        let legacy_path = home.join("todo_db").join("todo.sqlite");
+        if move_legacy_db(&legacy_path, &db_path)? {
+            eprintln!(
+                "Moved the database from '{}' to '{}'",
+                legacy_path.display(),
+                db_path.display()
+            );
+        }
+    }
+    Ok(db_path)
+}
+
+// Rebuilds the database file to reclaim the space left by deleted tasks,
+// returning its size in bytes before and after
+pub fn vacuum(conn: &Connection) -> Result<(u64, u64)> {
+    // VACUUM fails inside a transaction, and would commit it in older sqlite
+    if !conn.is_autocommit() {
+        return Err(TodoError::Validation(
+            "Cannot vacuum while a transaction is open".to_string(),
+        ));
+    }
+    let before = db_size(conn)?;
+    conn.execute_batch("VACUUM; PRAGMA optimize;")?;
+    Ok((before, db_size(conn)?))
+}
+
+// Runs `f` in a transaction, so several changes happen together or not at
+// all. Commits when `f` returns Ok and rolls back when it returns an error.
+// A transaction that fails because the database is busy is rolled back and
+// run again, since sqlite can't wait for the lock when a transaction that
+// started reading wants to write.
+pub fn with_transaction<T>(
+    conn: &mut Connection,
+    mut f: impl FnMut(&Transaction) -> Result<T>,
+) -> Result<T> {
+    retry_on_busy(|| {
+        let tx = conn.transaction()?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    })
+}
+
+// Runs `f` again, up to BUSY_RETRIES more times with a growing pause, while
+// it fails because another process holds the database lock
+pub fn retry_on_busy<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(error) if is_busy(&error) && attempt < BUSY_RETRIES => {
+                attempt += 1;
+                std::thread::sleep(BUSY_RETRY_PAUSE * attempt);
+            }
+            result => return result,
+        }
+    }
+}
+
+// Runs a single change with retry_on_busy, unless it's part of a transaction,
+// which has to be run again as a whole (see with_transaction)
+fn retry_write<T>(conn: &Connection, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    if conn.is_autocommit() {
+        retry_on_busy(f)
+    } else {
+        f()
+    }
+}
+
+// Size of the database in bytes, also known for in-memory databases
+fn db_size(conn: &Connection) -> Result<u64> {
+    let page_count: u64 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+    let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    Ok(page_count * page_size)
+}
+
+// Copies the database to a timestamped file in `dir` with sqlite's online
+// backup API, which is safe while other processes are using it.
+// Returns the path of the new file.
+pub fn backup(conn: &Connection, dir: &Path) -> Result<PathBuf> {
+    let file_name = format!("todo-{}.sqlite", Local::now().format("%Y%m%d-%H%M%S"));
+    let backup_path = dir.join(file_name);
+    if backup_path.exists() {
+        return Err(TodoError::Validation(format!(
+            "'{}' already exists",
+            backup_path.display()
+        )));
+    }
+    conn.backup(DatabaseName::Main, &backup_path, None)?;
+    Ok(backup_path)
+}
+
+// Returns a connection, creating the database if needed
+pub fn get_connection() -> Result<Connection> {
+    get_connection_at(&resolve_db_path(None, env_var)?)
+}
+
+// Returns a connection to the database at the given path, creating it if needed
+pub fn get_connection_at(db_path: &Path) -> Result<Connection> {
+    // ":memory:" runs against a throwaway database that never touches disk
+    if db_path == Path::new(MEMORY_DB_PATH) {
+        let conn = Connection::open_in_memory()?;
+        verify_db(&conn)?;
+        return Ok(conn);
+    }
+    if let Some(db_folder) = db_path
+        .parent()
+        .filter(|folder| !folder.as_os_str().is_empty())
+    {
+        verify_db_path(&db_folder.to_string_lossy())?;
+    }
+    let conn =
+        Connection::open(db_path).map_err(|error| explain_open_error(db_path, error.into()))?;
+    // Waits for other processes using the same file instead of failing at once
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    apply_pragmas(
+        &conn,
+        env::var("TODO_JOURNAL_MODE").ok().as_deref(),
+        env::var("TODO_SYNCHRONOUS").ok().as_deref(),
+    )
+    .and_then(|_| verify_db(&conn))
+    .map_err(|error| explain_open_error(db_path, error))?;
+    Ok(conn)
+}
+
+// sqlite only says "unable to open database file" when it can't create or
+// write the file (or its journal), so this names the path and the likely cause
+fn explain_open_error(db_path: &Path, error: TodoError) -> TodoError {
+    let cause = match &error {
+        TodoError::Db(db_error) => db_error.sqlite_error_code(),
+        _ => None,
+    };
+    match cause {
+        Some(
+            rusqlite::ErrorCode::CannotOpen
+            | rusqlite::ErrorCode::ReadOnly
+            | rusqlite::ErrorCode::PermissionDenied,
+        ) => TodoError::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Could not open the database '{}': check that you can write to it and to its folder ({})",
+                db_path.display(),
+                error
+            ),
+        )),
+        _ => error,
+    }
+}
+
+// Values accepted for the journal_mode and synchronous pragmas
+const JOURNAL_MODES: &[&str] = &["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"];
+const SYNCHRONOUS_MODES: &[&str] = &["OFF", "NORMAL", "FULL", "EXTRA"];
+
+// Sets how sqlite journals and syncs writes, WAL and NORMAL when not given.
+// Values are checked against what sqlite accepts, ignoring case.
+pub fn apply_pragmas(
+    conn: &Connection,
+    journal_mode: Option<&str>,
+    synchronous: Option<&str>,
+) -> Result<()> {
+    fn checked(name: &str, value: &str, allowed: &[&str]) -> Result<String> {
+        let value = value.trim().to_uppercase();
+        if allowed.contains(&value.as_str()) {
+            Ok(value)
+        } else {
+            Err(TodoError::Validation(format!(
+                "Invalid {} '{}', expected one of {}",
+                name,
+                value,
+                allowed.join(", ")
+            )))
+        }
+    }
+    let journal_mode = checked("journal mode", journal_mode.unwrap_or("WAL"), JOURNAL_MODES)?;
+    let synchronous = checked(
+        "synchronous",
+        synchronous.unwrap_or("NORMAL"),
+        SYNCHRONOUS_MODES,
+    )?;
+    // journal_mode answers with the mode in use, which we don't need
+    conn.pragma_update_and_check(None, "journal_mode", &journal_mode, |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", &synchronous)?;
+    Ok(())
+}
+
+// Prints every statement run on the connection, with how long it took, to stderr
+pub fn log_sql(conn: &mut Connection) {
+    fn log(sql: &str, elapsed: Duration) {
+        let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+        eprintln!("{}", style(format!("[{:>9.3?}] {}", elapsed, sql)).dim());
+    }
+    conn.profile(Some(log));
+}
+
+// How long a connection waits for a lock held by another process
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often retry_on_busy tries again, and how long it waits the first time
+pub const BUSY_RETRIES: u32 = 3;
+const BUSY_RETRY_PAUSE: Duration = Duration::from_millis(50);
+
+// Tells whether an error comes from the database still being locked by
+// another process after the busy timeout
+pub fn is_busy(error: &TodoError) -> bool {
+    match error {
+        TodoError::Db(error) => matches!(
+            error.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+        ),
+        _ => false,
+    }
+}
+
+// Aux function that creates tables if they don't exist, and brings databases
+// made by older versions up to date
+pub fn verify_db(conn: &Connection) -> Result<()> {
+    migrations::migrate(conn)?;
+    // Needed for subtasks to be deleted along with their parent
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    Ok(())
+}
+
+// Aux function that creates the folder where the DB should be stored,
+// and any missing parents, if it doesn't exist
+pub fn verify_db_path(db_folder: &str) -> Result<()> {
+    create_private_dir(Path::new(db_folder)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Could not create the folder '{}': {}", db_folder, e),
+        )
+    })?;
+    Ok(())
+}
+
+// Creates a folder and its missing parents. On unix only the user can read
+// the folders created, since the tasks in them may be private.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+// Asks a yes/no question and reads the answer from `input`, which is stdin
+// outside of tests. Anything but "y" or "yes", including no answer at all,
+// counts as no.
+pub fn confirm(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    prompt: &str,
+) -> io::Result<bool> {
+    write!(output, "{} [y/N] ", prompt)?;
+    output.flush()?;
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        // Ends the prompt line, since nobody typed Enter
+        writeln!(output)?;
+    }
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Prints help with a list of commands and parameters
+pub fn help() -> Result<()> {
+    let help_title = "\nAvailable commands:";
+    let help_text = r#"    
+        - add [TASK] [--at DATE] [--force] [--allow-duplicates]
+            Ads new task/s, optionally with their original creation date.
+            Tasks whose name already exists are refused unless duplicates are allowed.
+            Names longer than TODO_MAX_NAME_LEN (500 by default) and future --at dates are refused unless forced.
+            "due:DATE", "prio:high|medium|low" and "+tag" words set those fields,
+            a leading backslash keeps a word as part of the name.
+            Example: todo add "Build a tree"
+            Example: todo add "Pay rent due:2024-05-01 prio:high +home"
+            Example: todo add "Old task" --at 2023-11-02
+
+        - add - [--force] [--allow-duplicates]
+            Adds one task per line read from stdin, in the same syntax, all or none of them.
+            Blank lines and lines starting with # are skipped, and so are repeated names
+            unless duplicates are allowed.
+            Example: cat tasks.txt | todo add -
+
+        - sub [PARENT_ID] [TASK]
+            Adds a subtask under an existing task
+            Example: todo sub 2 "Dig a hole"
+
+        - list [--tree] [--limit N] [--offset N]
+            Lists all tasks, optionally with subtasks grouped under their parent
+            or only a window of them
+            Example: todo list --tree
+            Example: todo list --limit 10 --offset 20
+
+        - list [--format table|plain|csv|json]
+            Lists tasks as a colored table (the default), tab separated lines, CSV or JSON
+            Example: todo list --format csv > todo.csv
+
+        - list [--pending | --done | --completed]
+            Lists only the pending or the completed tasks (--completed is the same as --done)
+            Example: todo list --pending
+
+        - list [--search TEXT]
+            Lists the tasks whose name contains TEXT, ignoring case.
+            Can be combined with --pending, --done, --limit and --offset.
+            Example: todo list --pending --search milk --limit 5
+
+        - list [--list NAME]
+            Lists only the tasks in a named list (see move-list)
+            Example: todo list --list work
+
+        - list [--sort id|status|date|newest]
+            Lists tasks by id (the default), pending first, oldest or newest first
+            Example: todo list --sort newest
+
+        - list [--since DATE] [--until DATE]
+            Lists the tasks added within a date range
+            Can be combined with the other list options.
+            Example: todo list --since 2024-01-01 --until 2024-02-01
+
+        - recent [N]
+            Lists the N most recently added tasks, newest first (5 by default)
+            Example: todo recent 10
+
+        - stats
+            Shows how many tasks are pending and done
+            Example: todo stats
+
+        - show [ID]
+            Shows a single task
+            Example: todo show 2
+            show, toggle and rm also accept the start of a task's name instead of its id
+            Example: todo toggle "buy mi"
+
+        - toggle [ID]
+            Toggles the status of a task (Done/Pending)
+            Example: todo toggle 2
+        
+        - rename [ID] [NAME]
+            Changes the name of a task
+            Example: todo rename 2 "Build a bigger tree"
+
+        - rm [ID]... [--dry-run]
+            Removes tasks and their subtasks, or only lists them with --dry-run
+            Example: todo rm 4
+            Example: todo rm 4 5 --dry-run
+        
+        - due [ID] [DATE]
+            Sets the date a task is due
+            Example: todo due 2 2024-05-01
+
+        - move-list [ID] [LIST] (or reassign)
+            Moves a task to a named list, creating the list if it's new
+            Example: todo move-list 2 work
+
+        - color [ID] [red|yellow|green|blue|none]
+            Labels a task with a color, shown on its id in the list, or removes the label
+            Example: todo color 2 blue
+
+        - snooze [ID] [DAYS] (or defer)
+            Pushes the due date back by DAYS (1 by default), counting from today if there's none
+            Example: todo snooze 2 7
+
+        - export --format [ics|todotxt|csv|json]
+            Prints all tasks as an iCalendar, todo.txt, CSV or JSON file
+            Example: todo export --format ics > todo.ics
+
+        - import [FILE]
+            Adds the tasks from a todo.txt file
+            Example: todo import todo.txt
+
+        - clear [--dry-run]
+            Removes all completed tasks, or only lists them with --dry-run
+
+        - backup [DIR]
+            Copies the database to DIR/todo-YYYYMMDD-HHMMSS.sqlite (DIR defaults to .)
+            Example: todo backup ~/backups
+
+        - vacuum (or compact)
+            Compacts the database file after many deletions
+
+        - doctor
+            Checks the database for corruption and an out of date schema, without changing it
+            Example: todo doctor
+
+        - sort
+            Sorts completed and uncompleted tasks
+        
+        - demo
+            Shows a few sample tasks from a list kept in memory, without touching your tasks
+
+        - tui
+            Opens an interactive list (needs the "tui" feature)
+
+        - reset [--dry-run] [--yes]
+            Deletes all tasks after asking for confirmation, or only lists them with --dry-run.
+            --yes (-y) skips the question. Without a terminal to ask on (e.g. in scripts)
+            nothing is reset unless --yes is given.
+
+        Options for every command:
+            --db PATH       uses the database at PATH instead of the default one
+            --ascii         marks tasks with [x] and [ ] instead of ✓ and ○
+            --verbose (-v)  prints each SQL statement and how long it took to stderr
+
+        Exit codes:
+            0   success
+            1   the task (or file) was not found
+            2   usage error, e.g. a missing argument or an invalid value
+            3   database error
+        "#;
+
+    println!("{}", style(help_title).cyan().bright());
+    println!("{}", style(help_text).green());
+    Ok(())
+}
+
+// Looks up an environment variable, treating empty values as not set
+pub fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+// Get the user's home directory on the platform `os` (as in env::consts::OS),
+// reading environment variables with `var`
+fn home_dir(os: &str, var: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    if os == "windows" {
+        if let Some(profile) = var("USERPROFILE") {
+            return Ok(PathBuf::from(profile));
+        }
+        if let (Some(drive), Some(path)) = (var("HOMEDRIVE"), var("HOMEPATH")) {
+            return Ok(PathBuf::from(format!("{}{}", drive, path)));
+        }
+    }
+    var("HOME").map(PathBuf::from).ok_or_else(|| {
+        TodoError::Home(if os == "windows" {
+            "none of USERPROFILE, HOMEDRIVE and HOMEPATH or HOME are set".to_string()
+        } else {
+            "HOME is not set".to_string()
+        })
+    })
+}
+
+// The folder the database goes in by default: $XDG_STATE_HOME/todo when that's
+// set, otherwise ~/.local/state/todo on Linux and other unixes,
+// ~/Library/Application Support/todo on macOS and %LOCALAPPDATA%\todo on Windows
+fn data_dir(os: &str, var: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    // Relative paths are invalid in XDG variables and should be ignored
+    if let Some(state_home) = var("XDG_STATE_HOME").filter(|dir| Path::new(dir).is_absolute()) {
+        return Ok(PathBuf::from(state_home).join("todo"));
+    }
+    let base = match os {
+        "windows" => match var("LOCALAPPDATA") {
+            Some(local) => PathBuf::from(local),
+            None => home_dir(os, &var)?.join("AppData").join("Local"),
+        },
+        "macos" => home_dir(os, &var)?
+            .join("Library")
+            .join("Application Support"),
+        _ => home_dir(os, &var)?.join(".local").join("state"),
+    };
+    Ok(base.join("todo"))
+}
+
+// Moves a database from where older versions kept it to `db_path`, along
+// with its WAL files, if there's one there and nothing at `db_path` yet.
+// Returns whether it moved anything.
+fn move_legacy_db(legacy_path: &Path, db_path: &Path) -> Result<bool> {
+    if db_path.exists() || !legacy_path.is_file() {
+        return Ok(false);
+    }
+    if let Some(db_folder) = db_path.parent() {
+        create_private_dir(db_folder)?;
+    }
+    for suffix in ["", "-wal", "-shm"] {
+        let from = PathBuf::from(format!("{}{}", legacy_path.display(), suffix));
+        let to = PathBuf::from(format!("{}{}", db_path.display(), suffix));
+        if from.exists() {
+            // A rename can't cross file systems, a copy can
+            if fs::rename(&from, &to).is_err() {
+                fs::copy(&from, &to)?;
+                fs::remove_file(&from)?;
+            }
+        }
+    }
+    // The old folder is only removed when nothing else was left in it
+    if let Some(legacy_folder) = legacy_path.parent() {
+        let _ = fs::remove_dir(legacy_folder);
+    }
+    Ok(true)
+}
+
+/* -----------------------------------------------------------
+ *    Tests
+ * ---------------------------------------------------------*/
+
+#[cfg(test)]
+// Lints the original tests were written before, kept as they are
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::needless_borrows_for_generic_args
+)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use std::sync::Mutex;
+
+    // Creates a persistant in memory db connection
+    // creates tables if necessary
+    lazy_static! {
+        static ref DATABASE_CONNECTION: Mutex<Connection> = {
+            let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+            verify_db(&conn).expect("Cannot create tables");
+            Mutex::new(conn)
+        };
+    }
+
+    #[test]
+    fn test_apply_pragmas() {
+        let db_folder = tempfile::tempdir().expect("Could not create a temp dir");
+        let conn = get_connection_at(&db_folder.path().join("todo.sqlite"))
+            .expect("Could not open the db");
+        let pragma = |name: &str| -> String {
+            conn.pragma_query_value(None, name, |row| row.get::<_, rusqlite::types::Value>(0))
+                .map(|value| match value {
+                    rusqlite::types::Value::Text(text) => text,
+                    value => format!("{:?}", value),
+                })
+                .expect("Could not read the pragma")
+        };
+        assert_eq!(pragma("journal_mode"), "wal");
+        assert_eq!(
+            pragma("synchronous"),
+            "Integer(1)",
+            "synchronous is not NORMAL"
+        );
+
+        apply_pragmas(&conn, Some("delete"), Some("FULL")).expect("Could not set pragmas");
+        assert_eq!(pragma("journal_mode"), "delete");
+        assert_eq!(
+            pragma("synchronous"),
+            "Integer(2)",
+            "synchronous is not FULL"
+        );
+
+        assert!(matches!(
+            apply_pragmas(&conn, Some("sideways"), None),
+            Err(TodoError::Validation(_))
+        ));
+        assert!(apply_pragmas(&conn, None, Some("0; DROP TABLE todo")).is_err());
+        assert_eq!(
+            pragma("journal_mode"),
+            "delete",
+            "A bad value changed the pragma"
+        );
+    }
+
+    #[test]
+    fn test_busy_database_waits_for_lock() {
+        let db_folder = tempfile::tempdir().expect("Could not create a temp dir");
+        let db_path = db_folder.path().join("todo.sqlite");
+        let writer = get_connection_at(&db_path).expect("Could not open the db");
+        let conn = get_connection_at(&db_path).expect("Could not open the db");
+
+        // Another process holds the write lock for a moment
+        writer
+            .execute_batch("BEGIN IMMEDIATE")
+            .expect("Could not lock the db");
+        let started = std::time::Instant::now();
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            writer
+                .execute_batch("COMMIT")
+                .expect("Could not unlock the db");
+        });
+
+        Todo::add(&conn, "Waits for the lock").expect("Add should wait for the lock");
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        holder.join().expect("Lock holder panicked");
+
+        // Without a timeout the same write fails straight away with BUSY
+        let writer = get_connection_at(&db_path).expect("Could not open the db");
+        writer
+            .execute_batch("BEGIN IMMEDIATE")
+            .expect("Could not lock the db");
+        conn.busy_timeout(Duration::ZERO)
+            .expect("Could not set the timeout");
+        let error = Todo::add(&conn, "Fails").expect_err("Add should fail while locked");
+        assert!(is_busy(&error), "Unexpected error: {:?}", error);
+    }
+
+    #[test]
+    fn test_readers_and_writers_share_a_locked_db() {
+        let db_folder = tempfile::tempdir().expect("Could not create a temp dir");
+        let db_path = db_folder.path().join("todo.sqlite");
+        let writer = get_connection_at(&db_path).expect("Could not open the db");
+        let mut conn = get_connection_at(&db_path).expect("Could not open the db");
+        Todo::add(&conn, "Before").expect("Could not add todo");
+
+        // Another process is in the middle of writing
+        writer
+            .execute_batch("BEGIN IMMEDIATE; INSERT INTO todo (name) VALUES ('Uncommitted');")
+            .expect("Could not lock the db");
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            writer
+                .execute_batch("COMMIT")
+                .expect("Could not unlock the db");
+        });
+
+        // Reading doesn't wait, and doesn't see the uncommitted row
+        let started = std::time::Instant::now();
+        let todos = Todo::list(&conn, false).expect("Could not read while locked");
+        assert_eq!(todos.len(), 1);
+        assert!(
+            started.elapsed() < Duration::from_millis(150),
+            "The read waited"
+        );
+
+        // Without a busy timeout, the retries outlast the lock
+        conn.busy_timeout(Duration::ZERO)
+            .expect("Could not set the timeout");
+        with_transaction(&mut conn, |tx| {
+            Todo::rename(tx, todos[0].id, "Renamed")?;
+            Todo::add(tx, "After")
+        })
+        .expect("The write was not retried");
+        holder.join().expect("Lock holder panicked");
+
+        let names: Vec<String> = Todo::list(&conn, false)
+            .expect("Failed to list todo")
+            .into_iter()
+            .map(|todo| todo.name)
+            .collect();
+        assert_eq!(names, ["Renamed", "Uncommitted", "After"]);
+    }
+
+    #[test]
+    fn test_retry_on_busy_gives_up() {
+        let mut attempts = 0;
+        let result: Result<()> = retry_on_busy(|| {
+            attempts += 1;
+            Err(TodoError::Db(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            )))
+        });
+        assert!(result.as_ref().is_err_and(is_busy));
+        assert_eq!(attempts, BUSY_RETRIES + 1);
+
+        // Other errors are not retried
+        attempts = 0;
+        let result: Result<()> = retry_on_busy(|| {
+            attempts += 1;
+            Err(TodoError::NotFound(1))
+        });
+        assert!(matches!(result, Err(TodoError::NotFound(1))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_retry_write_leaves_transactions_to_with_transaction() {
+        let mut conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        let attempts = std::cell::Cell::new(0);
+        let busy = || -> Result<()> {
+            attempts.set(attempts.get() + 1);
+            Err(TodoError::Db(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            )))
+        };
+        assert!(retry_write(&conn, busy).is_err());
+        assert_eq!(
+            attempts.get(),
+            BUSY_RETRIES + 1,
+            "A single change was not retried"
+        );
+
+        attempts.set(0);
+        let tx = conn.transaction().expect("Could not start a transaction");
+        assert!(retry_write(&tx, busy).is_err());
+        assert_eq!(
+            attempts.get(),
+            1,
+            "A change in a transaction was retried alone"
+        );
+    }
+
+    #[test]
+    fn test_confirm() {
+        for (answer, expected) in [
+            ("y\n", true),
+            ("YES\n", true),
+            ("n\n", false),
+            ("\n", false),
+            ("", false),
+            ("maybe\n", false),
+        ] {
+            let mut output = Vec::new();
+            let confirmed = confirm(&mut answer.as_bytes(), &mut output, "Reset?")
+                .expect("Could not ask for confirmation");
+            assert_eq!(confirmed, expected, "Wrong result for {:?}", answer);
+            assert!(String::from_utf8_lossy(&output).starts_with("Reset? [y/N] "));
+        }
+    }
+
+    fn reset_db(conn: &Connection) -> Result<()> {
+        conn.execute("DELETE FROM todo", ())?;
+        Ok(())
+    }
+
+    fn contains_task(todos: &Vec<Todo>, target_name: &str) -> bool {
+        for todo in todos {
+            if todo.name == target_name {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn test_add_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        // Call the add function to add a todo
+        let name = "Test Todo";
+        Todo::add(&conn, name).expect("Failed to add todo");
+
+        // Query the database to check if the todo was added
+        let mut stmt = conn
+            .prepare("SELECT COUNT(*) FROM todo WHERE name = ?")
+            .expect("Failed to prepare statement");
+        let count: i32 = stmt
+            .query_row(&[name], |row| row.get(0))
+            .expect("Failed to query database");
+
+        assert_eq!(count, 1, "Todo was not added to the database");
+    }
+
+    #[test]
+    fn test_add_validates_name() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        assert!(Todo::add(&conn, "").is_err(), "An empty name was accepted");
+        assert!(
+            Todo::add(&conn, " \t ").is_err(),
+            "A blank name was accepted"
+        );
+        Todo::add(&conn, "  Padded  ").expect("Could not add todo");
+
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        assert_eq!(todos.len(), 1, "Invalid names were added to the database");
+        assert_eq!(todos[0].name, "Padded", "The name was not trimmed");
+    }
+
+    #[test]
+    fn test_add_many() {
+        let mut conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let added =
+            Todo::add_many(&mut conn, &["Task 1", " Task 2 "]).expect("Could not add todos");
+        let names: Vec<&str> = added.iter().map(|todo| todo.name.as_str()).collect();
+        assert_eq!(names, ["Task 1", "Task 2"]);
+        assert_eq!(
+            Todo::list(&conn, false).expect("Failed to list todo").len(),
+            2
+        );
+
+        // A bad name halfway through rolls back the whole batch
+        let result = Todo::add_many(&mut conn, &["Task 3", "", "Task 4"]);
+        assert!(result.is_err(), "An empty name was accepted");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        assert_eq!(todos.len(), 2, "Part of a failed batch was added");
+    }
+
+    // Slow, so only run on demand: cargo test --release -- --ignored add_many_speed
+    #[test]
+    #[ignore]
+    fn test_add_many_speed() {
+        let names: Vec<String> = (1..=10_000).map(|i| format!("Task {}", i)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let db_folder = tempfile::tempdir().expect("Could not create a temp dir");
+        let mut conn = get_connection_at(&db_folder.path().join("todo.sqlite"))
+            .expect("Could not open the db");
+
+        let start = std::time::Instant::now();
+        for name in &names {
+            Todo::add(&conn, name).expect("Could not add todo");
+        }
+        let one_by_one = start.elapsed();
+        let start = std::time::Instant::now();
+        Todo::add_many(&mut conn, &names).expect("Could not add todos");
+        let batched = start.elapsed();
+
+        println!(
+            "10k inserts: {:?} one by one, {:?} in one transaction",
+            one_by_one, batched
+        );
+        assert_eq!(Todo::stats(&conn).expect("No stats").total, 20_000);
+        assert!(batched < one_by_one, "The transaction was not faster");
+    }
+
+    #[test]
+    fn test_errors_are_typed() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let error = Todo::get(&conn, 9999).expect_err("Found a missing task");
+        assert!(matches!(error, TodoError::NotFound(9999)));
+        assert_eq!(error.to_string(), "No task with id 9999");
+        assert!(matches!(
+            Todo::add(&conn, " "),
+            Err(TodoError::Validation(_))
+        ));
+
+        // The underlying error stays reachable through std::error::Error
+        let error = TodoError::from(conn.execute("SELECT * FROM missing", []).unwrap_err());
+        assert!(matches!(error, TodoError::Db(_)));
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn test_with_transaction_rolls_back_on_error() {
+        let mut conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+        let kept = Todo::add(&conn, "Kept").expect("Could not add todo");
+
+        let result: Result<()> = with_transaction(&mut conn, |tx| {
+            Todo::toggle(tx, kept.id)?;
+            Todo::add(tx, "Added")?;
+            Todo::rm(tx, kept.id)?;
+            Todo::rm(tx, 9999)
+        });
+        assert!(matches!(result, Err(TodoError::NotFound(9999))));
+
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        assert_eq!(todos.len(), 1, "The transaction was not rolled back");
+        assert_eq!(todos[0].id, kept.id);
+        assert_eq!(todos[0].is_done, 0, "The toggle was not rolled back");
+
+        with_transaction(&mut conn, |tx| Todo::toggle(tx, kept.id)).expect("Could not toggle");
+        assert_eq!(
+            Todo::get(&conn, kept.id)
+                .expect("Could not get todo")
+                .is_done,
+            1
+        );
+    }
+
+    #[test]
+    fn test_list_page() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        for name in ["Buy milk", "Buy bread", "Sell 50% of stock", "buy eggs"] {
+            Todo::add(&conn, name).expect("Could not add todo");
+        }
+        let ids: Vec<i32> = Todo::list(&conn, false)
+            .expect("Failed to list todo")
+            .iter()
+            .map(|todo| todo.id)
+            .collect();
+        Todo::toggle(&conn, ids[0]).expect("Could not toggle todo");
+        let names = |filter: ListFilter| -> Vec<String> {
+            Todo::list_page(&conn, &filter)
+                .expect("Failed to list todo")
+                .into_iter()
+                .map(|todo| todo.name)
+                .collect()
+        };
+
+        assert_eq!(names(ListFilter::default()).len(), 4);
+        assert_eq!(
+            names(ListFilter {
+                search: Some("BUY".to_string()),
+                status: Some(false),
+                ..ListFilter::default()
+            }),
+            ["Buy bread", "buy eggs"]
+        );
+        assert_eq!(
+            names(ListFilter {
+                search: Some("buy".to_string()),
+                offset: 1,
+                limit: Some(1),
+                ..ListFilter::default()
+            }),
+            ["Buy bread"]
+        );
+        assert_eq!(
+            names(ListFilter {
+                sort: ListSort::Status,
+                limit: Some(3),
+                ..ListFilter::default()
+            }),
+            ["Buy bread", "Sell 50% of stock", "buy eggs"]
+        );
+        // Wildcards in the search text match literally
+        assert_eq!(
+            names(ListFilter {
+                search: Some("0%".to_string()),
+                ..ListFilter::default()
+            }),
+            ["Sell 50% of stock"]
+        );
+        assert!(names(ListFilter {
+            search: Some("_".to_string()),
+            ..ListFilter::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_todo_serde_round_trip() {
+        let todo = sample_todos().remove(0);
+        let json = serde_json::to_value(&todo).expect("Could not serialize");
+        assert_eq!(json["completed"], false);
+        assert_eq!(json["date_added"], "2024-05-01T10:00:00Z");
+        assert!(json.get("is_done").is_none(), "is_done was not renamed");
+        let back: Todo = serde_json::from_value(json).expect("Could not deserialize");
+        assert_eq!(back, todo);
+
+        for todo in sample_todos() {
+            let json = serde_json::to_string(&todo).expect("Could not serialize");
+            let back: Todo = serde_json::from_str(&json).expect("Could not deserialize");
+            assert_eq!(back, todo, "{} did not round trip", json);
+        }
+
+        // Other offsets are stored in UTC
+        let json = r#"{"id":1,"name":"Late","date_added":"2024-01-01T01:30:00+02:00","completed":true,"parent_id":null,"due_date":null,"priority":null,"tags":[]}"#;
+        let todo: Todo = serde_json::from_str(json).expect("Could not deserialize");
+        assert_eq!(todo.date_added, utc("2023-12-31 23:30:00"));
+        assert_eq!(todo.is_done, 1);
+        assert!(serde_json::from_str::<Todo>(&json.replace("2024-01-01T", "yesterday ")).is_err());
+    }
+
+    #[test]
+    fn test_legacy_dates_are_normalized() {
+        let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        verify_db(&conn).expect("Cannot create tables");
+        // Formats older versions and other tools may have written
+        for date_added in [
+            "'2024-05-01T12:30:00+02:00'",
+            "'2024-05-01 10:30:00.250'",
+            "2460432.4375",
+        ] {
+            conn.execute(
+                &format!(
+                    "INSERT INTO todo (name, date_added) VALUES ('Old', {})",
+                    date_added
+                ),
+                [],
+            )
+            .expect("Could not insert legacy date");
+        }
+        let read: Vec<DateTime<Utc>> = Todo::list(&conn, false)
+            .expect("Legacy dates can't be read")
+            .iter()
+            .map(|todo| todo.date_added)
+            .collect();
+        assert_eq!(read[0], utc("2024-05-01 10:30:00"));
+        assert_eq!(
+            read[1],
+            utc("2024-05-01 10:30:00") + chrono::Duration::milliseconds(250)
+        );
+        assert_eq!(read[2], utc("2024-05-01 22:30:00"));
+    }
+
+    #[test]
+    fn test_set_color() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+        let id = Todo::add(&conn, "Paint the fence")
+            .expect("Could not add todo")
+            .id;
+
+        Todo::set_color(&conn, id, Some("Blue")).expect("Could not set the color");
+        let todo = Todo::get(&conn, id).expect("Failed to get todo");
+        assert_eq!(todo.color.as_deref(), Some("blue"));
+        assert_eq!(todo.is_done, 0, "The color changed the status");
+
+        assert!(matches!(
+            Todo::set_color(&conn, id, Some("mauve")),
+            Err(TodoError::Validation(_))
+        ));
+        assert!(matches!(
+            Todo::set_color(&conn, 9999, Some("red")),
+            Err(TodoError::NotFound(9999))
+        ));
+        assert_eq!(
+            Todo::get(&conn, id)
+                .expect("Failed to get todo")
+                .color
+                .as_deref(),
+            Some("blue"),
+            "A bad color replaced the label"
+        );
+
+        Todo::set_color(&conn, id, None).expect("Could not remove the color");
+        assert_eq!(
+            Todo::get(&conn, id).expect("Failed to get todo").color,
+            None
+        );
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+        Todo::add(&conn, "Healthy").expect("Could not add todo");
+
+        assert!(Todo::integrity_check(&conn).expect("Could not check the db"));
+    }
+
+    #[test]
+    fn test_stats() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let stats = Todo::stats(&conn).expect("Could not count todos");
+        assert_eq!(stats, Stats::default());
+        assert_eq!(stats.completion_rate(), 0.0);
+
+        for name in ["Task 1", "Task 2", "Task 3", "Task 4"] {
+            Todo::add(&conn, name).expect("Could not add todo");
+        }
+        let first = Todo::list(&conn, false).expect("Failed to list todo")[0].id;
+        Todo::toggle(&conn, first).expect("Could not toggle todo");
+
+        let stats = Todo::stats(&conn).expect("Could not count todos");
+        assert_eq!(
+            stats,
+            Stats {
+                pending: 3,
+                done: 1,
+                total: 4
+            }
+        );
+        assert_eq!(stats.completion_rate(), 0.25);
+    }
+
+    #[test]
+    fn test_add_rejects_long_names() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let long_name = "x".repeat(MAX_NAME_LEN + 1);
+        assert!(matches!(
+            Todo::add(&conn, &long_name),
+            Err(TodoError::Validation(_))
+        ));
+        let task = parse_task_input(&long_name);
+        let error = Todo::add_parsed(&conn, &task, None, false).expect_err("Accepted a long name");
+        assert!(error.to_string().contains("--force"), "{}", error);
+        assert!(Todo::list(&conn, false)
+            .expect("Failed to list todo")
+            .is_empty());
+
+        let todo = Todo::add_parsed(&conn, &task, None, true).expect("--force was refused");
+        assert_eq!(todo.name.len(), MAX_NAME_LEN + 1);
+        assert!(Todo::add_parsed(&conn, &parse_task_input(" "), None, true).is_err());
+
+        assert_eq!(parse_max_name_len(None).expect("No default"), MAX_NAME_LEN);
+        assert_eq!(
+            parse_max_name_len(Some(" 80 ")).expect("80 was refused"),
+            80
+        );
+        for bad in ["0", "-3", "lots"] {
+            assert!(
+                parse_max_name_len(Some(bad)).is_err(),
+                "{} was accepted",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_unique_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let first = Todo::add_unique(&conn, "Buy milk").expect("Could not add todo");
+        let second = Todo::add_unique(&conn, "buy MILK").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+
+        assert_eq!(first, None, "The first task was reported as a duplicate");
+        assert_eq!(second, Some(todos[0].id), "The duplicate was not detected");
+        assert_eq!(todos.len(), 1, "The duplicate was added anyway");
+    }
+
+    #[test]
+    fn test_list_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Task 1").expect("Could not add todo");
+        Todo::add(&conn, "Task 2").expect("Could not add todo");
+        Todo::add(&conn, "Task 3").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+
+        assert_eq!(
+            todos.len(),
+            3,
+            "Wrong number of todo items returned by list()"
+        );
+    }
+
+    #[test]
+    fn test_get_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Task 1").expect("Could not add todo");
+        let id = Todo::list(&conn, false).expect("Failed to list todo")[0].id;
+
+        let todo = Todo::get(&conn, id).expect("Failed to get todo");
+        assert_eq!(todo.name, "Task 1", "Wrong todo returned by get()");
+        assert!(
+            matches!(Todo::get(&conn, id + 1), Err(TodoError::NotFound(_))),
+            "get() found a todo that doesn't exist"
+        );
+    }
+
+    #[test]
+    fn test_statements_are_cached() {
+        // Todo's queries behave the same whether the cache keeps their
+        // statements or not, and reused ones never return stale rows
+        for capacity in [0, 16] {
+            let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+            conn.set_prepared_statement_cache_capacity(capacity);
+            verify_db(&conn).expect("Cannot create tables");
+            let task = parse_task_input("Task +home");
+            for expected in 1..=3 {
+                Todo::add(&conn, "Task").expect("Could not add todo");
+                let todo = Todo::add_parsed(&conn, &task, None, false).expect("Could not add todo");
+                assert_eq!(
+                    Todo::get(&conn, todo.id).expect("Failed to get todo").tags,
+                    ["home"]
+                );
+                assert_eq!(
+                    Todo::list(&conn, false).expect("Failed to list todo").len(),
+                    expected * 2,
+                    "Stale rows with a cache of {}",
+                    capacity
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_list_paginated_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        for i in 1..=100 {
+            Todo::add(&conn, &format!("Task {}", i)).expect("Could not add todo");
+        }
+        let todos = Todo::list_paginated(&conn, 20, Some(10), false).expect("Failed to list todo");
+        let names: Vec<String> = (21..=30).map(|i| format!("Task {}", i)).collect();
+
+        assert_eq!(
+            todos
+                .iter()
+                .map(|todo| todo.name.clone())
+                .collect::<Vec<_>>(),
+            names,
+            "Wrong window returned by list_paginated()"
+        );
+        assert_eq!(
+            Todo::list_paginated(&conn, 95, None, false)
+                .expect("Failed to list todo")
+                .len(),
+            5,
+            "An offset without a limit should return the remaining rows"
+        );
+    }
+
+    #[test]
+    fn test_list_filtered_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Pending 1").expect("Failed to add todo");
+        Todo::add(&conn, "Done 1").expect("Failed to add todo");
+        Todo::add(&conn, "Pending 2").expect("Failed to add todo");
+        Todo::add(&conn, "Done 2").expect("Failed to add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        Todo::toggle(&conn, todos[1].id).expect("Failed to toggle todo");
+        Todo::toggle(&conn, todos[3].id).expect("Failed to toggle todo");
+
+        let names = |status| -> Vec<String> {
+            Todo::list_filtered(&conn, status)
+                .expect("Failed to list todo")
+                .into_iter()
+                .map(|todo| todo.name)
+                .collect()
+        };
+        assert_eq!(names(Some(false)), vec!["Pending 1", "Pending 2"]);
+        assert_eq!(names(Some(true)), vec!["Done 1", "Done 2"]);
+        assert_eq!(names(None).len(), 4, "No filter should list every task");
+    }
+
+    #[test]
+    fn test_subtree_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Parent").expect("Failed to add todo");
+        Todo::add(&conn, "Other").expect("Failed to add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        let parent_id = todos[0].id;
+        Todo::add_subtask(&conn, parent_id, "Child").expect("Failed to add subtask");
+        let child_id = Todo::list(&conn, false).expect("Failed to list todo")[2].id;
+        Todo::add_subtask(&conn, child_id, "Grandchild").expect("Failed to add subtask");
+
+        let names: Vec<String> = Todo::subtree(&conn, parent_id)
+            .expect("Failed to list subtree")
+            .into_iter()
+            .map(|todo| todo.name)
+            .collect();
+        assert_eq!(names, vec!["Parent", "Child", "Grandchild"]);
+        assert!(
+            Todo::subtree(&conn, -1).is_err(),
+            "Missing ids should be reported"
+        );
+    }
+
+    #[test]
+    fn test_resolve_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Buy milk").expect("Failed to add todo");
+        Todo::add(&conn, "Buy bread").expect("Failed to add todo");
+        Todo::add(&conn, "Call 100%_done").expect("Failed to add todo");
+        Todo::add(&conn, "Read").expect("Failed to add todo");
+        Todo::add(&conn, "Read a book").expect("Failed to add todo");
+        let ids: Vec<i32> = Todo::list(&conn, false)
+            .expect("Failed to list todo")
+            .iter()
+            .map(|todo| todo.id)
+            .collect();
+
+        let resolve = |needle| Todo::resolve(&conn, needle).expect("Failed to resolve");
+        assert_eq!(resolve("buy mi"), Resolution::Unique(ids[0]));
+        assert_eq!(resolve("BUY"), Resolution::Ambiguous(vec![ids[0], ids[1]]));
+        assert_eq!(resolve("Call 100%_"), Resolution::Unique(ids[2]));
+        assert_eq!(
+            resolve("read"),
+            Resolution::Unique(ids[3]),
+            "Exact names should win"
+        );
+        assert_eq!(
+            resolve("milk"),
+            Resolution::None,
+            "Only prefixes should match"
+        );
+        assert_eq!(
+            resolve("Call 100_"),
+            Resolution::None,
+            "_ should not be a wildcard"
+        );
+        assert_eq!(resolve("  "), Resolution::None);
+    }
+
+    #[test]
+    fn test_add_returns_created_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let first = Todo::add(&conn, "  Buy milk ").expect("Failed to add todo");
+        assert_eq!(first.name, "Buy milk");
+        assert_eq!(first.is_done, 0);
+        assert!(
+            (Utc::now() - first.date_added).num_minutes() < 1,
+            "The timestamp was not read back"
+        );
+
+        let child = Todo::add_subtask(&conn, first.id, "Find a shop").expect("Failed to add");
+        assert!(child.id > first.id, "Ids are not increasing");
+        assert_eq!(child.parent_id, Some(first.id));
+
+        let task = parse_task_input("Old one +home");
+        let old = Todo::add_parsed(&conn, &task, Some("2023-11-02"), false).expect("Failed to add");
+        assert_eq!(old.date_added, utc("2023-11-02 00:00:00"));
+        assert_eq!(old.tags, vec!["home".to_string()]);
+
+        let stored = Todo::get(&conn, old.id).expect("Failed to get todo");
+        assert_eq!(stored.name, old.name);
+    }
+
+    #[test]
+    fn test_latest_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "First").expect("Failed to add todo");
+        Todo::add(&conn, "Second").expect("Failed to add todo");
+        Todo::add(&conn, "Third").expect("Failed to add todo");
+        Todo::add_with_date(&conn, "Backdated", "2023-11-02", false).expect("Failed to add todo");
+
+        let todos = Todo::latest(&conn, 2).expect("Failed to list todo");
+        let names: Vec<&str> = todos.iter().map(|todo| todo.name.as_str()).collect();
+        assert_eq!(names, vec!["Third", "Second"]);
+
+        let todos = Todo::latest(&conn, 10).expect("Failed to list todo");
+        assert_eq!(todos.len(), 4);
+        assert_eq!(todos[3].name, "Backdated", "Older tasks should come last");
+    }
+
+    #[test]
+    fn test_list_between_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add_with_date(&conn, "December", "2023-12-31 23:00:00", false)
+            .expect("Could not add todo");
+        Todo::add_with_date(&conn, "January", "2024-01-15", false).expect("Could not add todo");
+        let late_january = Todo::add_with_date(&conn, "Late January", "2024-01-31 18:30:00", false)
+            .expect("Could not add todo");
+        Todo::add_with_date(&conn, "February", "2024-02-02", false).expect("Could not add todo");
+
+        let todos = Todo::list_between(&conn, Some("2024-01-01"), Some("2024-01-31"))
+            .expect("Failed to list todo");
+        let names: Vec<&str> = todos.iter().map(|todo| todo.name.as_str()).collect();
+        assert_eq!(names, vec!["January", "Late January"], "Wrong date window");
+
+        let todos =
+            Todo::list_between(&conn, Some("2024-02-01"), None).expect("Failed to list todo");
+        assert_eq!(todos.len(), 1, "Open-ended range returned the wrong rows");
+
+        assert!(
+            Todo::list_between(&conn, Some("January"), None).is_err(),
+            "A malformed date was accepted"
+        );
+
+        // The range combines with the other filters
+        Todo::toggle(&conn, late_january.id).expect("Could not toggle todo");
+        let filter = ListFilter {
+            status: Some(false),
+            since: Some("2023-12-01".to_string()),
+            limit: Some(2),
+            sort: ListSort::Newest,
+            ..ListFilter::default()
+        };
+        let todos = Todo::list_page(&conn, &filter).expect("Failed to list todo");
+        let names: Vec<&str> = todos.iter().map(|todo| todo.name.as_str()).collect();
+        assert_eq!(names, ["February", "January"], "Filters were ignored");
+    }
+
+    #[test]
+    fn test_sort_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Task 1").expect("Could not add todo");
+        Todo::add(&conn, "Task 2").expect("Could not add todo");
+        Todo::add(&conn, "Task 3").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        // toggles the first entry
+        Todo::toggle(&conn, todos[0].id).expect("Could not toggle first todo");
+
+        // true means sorted by status
+        let todos = Todo::list(&conn, true).expect("Failed to sort todos");
+
+        assert_eq!(
+            todos[2].name, "Task 1",
+            "The todo marked as done was not the LAST one returned"
+        );
+
+        assert_eq!(
+            todos.len(),
+            3,
+            "Wrong number of todo items returned by sort()"
+        );
+    }
+
+    #[test]
+    fn test_rm_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Task 1").expect("Could not add todo");
+        Todo::add(&conn, "Task 2").expect("Could not add todo");
+        Todo::add(&conn, "Task 3").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        // toggles the first entry
+        Todo::rm(&conn, todos[0].id).expect("Could not remove first todo");
+
+        // true means sorted by status
+        let todos = Todo::list(&conn, false).expect("Failed to list todos");
+        dbg!(&todos);
+
+        assert_eq!(
+            todos.len(),
+            2,
+            "Wrong number of todo items returned by sort()"
+        );
+
+        assert_eq!(
+            contains_task(&todos, "Task 1"),
+            false,
+            "Task 1 was not deleted!"
+        );
+    }
+
+    #[test]
+    fn test_toggle_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Task 1").expect("Could not add todo");
+        Todo::add(&conn, "Task 2").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        // toggles the first entry
+        Todo::toggle(&conn, todos[0].id).expect("Could not toggle first todo");
+
+        // true means sorted by status
+        let todos = Todo::list(&conn, false).expect("Failed to sort todos");
+        dbg!(&todos);
+
+        assert_eq!(
+            todos.len(),
+            2,
+            "Wrong number of todo items returned by toggle()"
+        );
+
+        // True and False are stored as 0  or 1 in the db
+        assert_eq!(todos[0].is_done, 1, "Task 1 was not toggled!");
+    }
+
+    #[test]
+    fn test_subtask_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Parent").expect("Could not add todo");
+        Todo::add(&conn, "Other").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        Todo::add_subtask(&conn, todos[0].id, "Child").expect("Could not add subtask");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        Todo::add_subtask(&conn, todos[2].id, "Grandchild").expect("Could not add subtask");
+
+        let todos = Todo::tree_order(Todo::list(&conn, false).expect("Failed to list todo"));
+        let names: Vec<&str> = todos.iter().map(|todo| todo.name.as_str()).collect();
+
+        assert_eq!(
+            names,
+            vec!["Parent", "Child", "Grandchild", "Other"],
+            "Subtasks were not ordered under their parent"
+        );
+        assert_eq!(
+            todos[1].parent_id,
+            Some(todos[0].id),
+            "Wrong parent for Child"
+        );
+
+        assert!(
+            matches!(
+                Todo::add_subtask(&conn, 9999, "Orphan"),
+                Err(TodoError::NotFound(9999))
+            ),
+            "An unknown parent was not reported"
+        );
+        assert_eq!(
+            Todo::list(&conn, false).expect("Failed to list todo").len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_rm_cascades_to_subtasks() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Parent").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        Todo::add_subtask(&conn, todos[0].id, "Child").expect("Could not add subtask");
+        Todo::add(&conn, "Unrelated").expect("Could not add todo");
+
+        Todo::rm(&conn, todos[0].id).expect("Could not remove parent");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+
+        assert_eq!(todos.len(), 1, "Subtask was not deleted with its parent");
+        assert_eq!(todos[0].name, "Unrelated");
+    }
+
+    #[test]
+    fn test_would_cycle() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Parent").expect("Could not add todo");
+        Todo::add(&conn, "Other").expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        let (parent, other) = (todos[0].id, todos[1].id);
+        Todo::add_subtask(&conn, parent, "Child").expect("Could not add subtask");
+        let child = Todo::list(&conn, false).expect("Failed to list todo")[2].id;
+
+        assert!(Todo::would_cycle(&conn, parent, child).expect("Cycle check failed"));
+        assert!(Todo::would_cycle(&conn, parent, parent).expect("Cycle check failed"));
+        assert!(!Todo::would_cycle(&conn, child, other).expect("Cycle check failed"));
+    }
+
+    #[test]
+    fn test_move_to_list() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let id = Todo::add(&conn, "Write report")
+            .expect("Could not add todo")
+            .id;
+        Todo::add(&conn, "Buy milk").expect("Could not add todo");
+        Todo::move_to_list(&conn, id, "inbox").expect("Could not move the task");
+        let in_list = |list: &str| -> Vec<i32> {
+            let filter = ListFilter {
+                list: Some(list.to_string()),
+                ..ListFilter::default()
+            };
+            Todo::list_page(&conn, &filter)
+                .expect("Failed to list todo")
+                .iter()
+                .map(|todo| todo.id)
+                .collect()
+        };
+        assert_eq!(in_list("inbox"), [id]);
+
+        // The new list is created by moving a task to it
+        Todo::move_to_list(&conn, id, " work ").expect("Could not move the task");
+        assert_eq!(in_list("work"), [id], "Not in the new list");
+        assert!(in_list("inbox").is_empty(), "Still in the old list");
+        assert_eq!(
+            Todo::get(&conn, id)
+                .expect("Failed to get todo")
+                .list
+                .as_deref(),
+            Some("work")
+        );
+
+        assert!(matches!(
+            Todo::move_to_list(&conn, id, "  "),
+            Err(TodoError::Validation(_))
+        ));
+        assert!(matches!(
+            Todo::move_to_list(&conn, 9999, "work"),
+            Err(TodoError::NotFound(9999))
+        ));
+    }
+
+    #[test]
+    fn test_snooze_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Task 1").expect("Could not add todo");
+        let id = Todo::list(&conn, false).expect("Failed to list todo")[0].id;
+
+        let today = Local::now().date_naive();
+        let due = Todo::snooze(&conn, id, 2).expect("Could not snooze todo");
+        assert_eq!(
+            due,
+            today + chrono::Duration::days(2),
+            "Snoozing without a due date"
+        );
+
+        Todo::set_due(&conn, id, "2024-01-30").expect("Could not set due date");
+        Todo::snooze(&conn, id, 3).expect("Could not snooze todo");
+        let todo = Todo::get(&conn, id).expect("Failed to get todo");
+        assert_eq!(
+            todo.due_date.as_deref(),
+            Some("2024-02-02"),
+            "Month rollover"
+        );
+
+        Todo::set_due(&conn, id, "2023-12-30").expect("Could not set due date");
+        let due = Todo::snooze(&conn, id, 5).expect("Could not snooze todo");
+        assert_eq!(due.to_string(), "2024-01-04", "Year rollover");
+
+        assert!(matches!(
+            Todo::snooze(&conn, id + 1, 1),
+            Err(TodoError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_set_due_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Pay rent").expect("Could not add todo");
+        let id = Todo::list(&conn, false).expect("Failed to list todo")[0].id;
+        Todo::set_due(&conn, id, "2024-05-01").expect("Could not set due date");
+
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        assert_eq!(todos[0].due_date.as_deref(), Some("2024-05-01"));
+        assert!(
+            Todo::set_due(&conn, id, "2024-13-01").is_err(),
+            "An invalid due date was accepted"
+        );
+    }
+
+    #[test]
+    fn test_export_ical() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add(&conn, "Pay rent").expect("Could not add todo");
+        Todo::add(&conn, &"Long summary, ".repeat(10)).expect("Could not add todo");
+        Todo::add_with_date(
+            &conn,
+            "Line one\r\nline two\rthree",
+            "2023-11-02T08:30:00",
+            false,
+        )
+        .expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        Todo::set_due(&conn, todos[0].id, "2024-05-01").expect("Could not set due date");
+        Todo::toggle(&conn, todos[0].id).expect("Could not toggle todo");
+
+        let ical = Todo::export_ical(&conn).expect("Failed to export todos");
+        let lines: Vec<&str> = ical.split("\r\n").collect();
+
+        assert!(ical.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert_eq!(lines.iter().filter(|l| **l == "BEGIN:VTODO").count(), 3);
+        assert!(lines.contains(&format!("UID:todo-{}@rust-todo-list", todos[0].id).as_str()));
+        assert!(lines.contains(&"SUMMARY:Pay rent"));
+        assert!(lines.contains(&"DUE;VALUE=DATE:20240501"));
+        assert!(lines.contains(&"STATUS:COMPLETED"));
+        assert!(lines.contains(&"STATUS:NEEDS-ACTION"));
+        assert!(lines.contains(&"SUMMARY:Line one\\nline two\\nthree"));
+        assert!(
+            !ical.replace("\r\n", "").contains('\r'),
+            "A raw CR was written"
+        );
+        assert!(lines.contains(&"CREATED:20231102T083000Z"));
+        // DTSTAMP is when the export ran, not when the task was added
+        let stamps: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("DTSTAMP:"))
+            .collect();
+        assert_eq!(stamps.len(), 3);
+        let stamp = NaiveDateTime::parse_from_str(stamps[2], "%Y%m%dT%H%M%SZ")
+            .expect("DTSTAMP is not a UTC date-time");
+        assert!(Utc::now().naive_utc() - stamp < chrono::Duration::minutes(1));
+        assert!(
+            lines.iter().all(|line| line.len() <= 75),
+            "Long lines were not folded"
+        );
+        assert!(lines.iter().any(|line| line.starts_with(' ')));
+    }
+
+    #[test]
+    fn test_add_with_date_todo() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        Todo::add_with_date(&conn, "Old task", "2023-11-02", false).expect("Could not add todo");
+        Todo::add_with_date(&conn, "Older task", "2023-10-01T08:30:00", false)
+            .expect("Could not add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+
+        assert_eq!(todos[0].date_added, utc("2023-11-02 00:00:00"));
+        assert_eq!(todos[1].date_added, utc("2023-10-01 08:30:00"));
+        assert!(
+            Todo::add_with_date(&conn, "Bad", "02/11/2023", false).is_err(),
+            "A malformed date was accepted"
+        );
+        assert!(
+            Todo::add_with_date(&conn, "Future", "2999-01-01", false).is_err(),
+            "A future date was accepted without force"
+        );
+        Todo::add_with_date(&conn, "Future", "2999-01-01", true).expect("Force was not honored");
+        assert_eq!(
+            Todo::list(&conn, false).expect("Failed to list todo").len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_todotxt_round_trip() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let input = "(A) 2023-11-02 Call mom @phone +family\n\
+                     2023-11-03 Pay rent due:2024-05-01\n\
+                     x Buy milk @store\n\
+                     x 2023-11-05 2023-11-04 Water plants\n";
+        let count = Todo::import_todotxt(&conn, input).expect("Failed to import todos");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+
+        assert_eq!(count, 4, "Wrong number of tasks imported");
+        assert_eq!(todos[0].name, "Call mom @phone");
+        assert_eq!(todos[0].priority.as_deref(), Some("high"));
+        assert_eq!(todos[0].tags, vec!["family".to_string()]);
+        assert_eq!(todos[0].date_added, utc("2023-11-02 00:00:00"));
+        assert_eq!(todos[1].due_date.as_deref(), Some("2024-05-01"));
+        assert_eq!(todos[2].is_done, 1);
+        assert_eq!(todos[3].date_added, utc("2023-11-04 00:00:00"));
+
+        let output = Todo::export_todotxt(&conn).expect("Failed to export todos");
+        assert_eq!(
+            output,
+            "(A) 2023-11-02 Call mom @phone +family\n\
+             2023-11-03 Pay rent due:2024-05-01\n\
+             x Buy milk @store\n\
+             x Water plants\n"
+        );
+    }
+
+    #[test]
+    fn test_import_todotxt_rejects_empty_names() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let input = "Buy milk\n\n2023-11-02 +home\n";
+        match Todo::import_todotxt(&conn, input) {
+            Err(TodoError::Validation(message)) => {
+                assert!(
+                    message.starts_with("Line 3:"),
+                    "No line number: {}",
+                    message
+                )
+            }
+            other => panic!("The empty name was imported: {:?}", other),
+        }
+        assert!(
+            Todo::list(&conn, false)
+                .expect("Failed to list todo")
+                .is_empty(),
+            "The import was not rolled back"
+        );
+    }
+
+    #[test]
+    fn test_add_task_lines() {
+        let mut conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let input =
+            "Buy milk +home\n\n   \n# a comment\n  Pay rent due:2024-05-01 \n\\#3 is a name\n";
+        let tasks = parse_task_lines(input);
+        let added = Todo::add_all(&mut conn, &tasks, false).expect("Could not add the tasks");
+        assert_eq!(added.len(), 3, "Blank lines or comments were added");
+        assert_eq!(added[0].tags, ["home"]);
+        assert_eq!(added[1].due_date.as_deref(), Some("2024-05-01"));
+        assert_eq!(added[2].name, "#3 is a name");
+        assert_eq!(
+            Todo::list(&conn, false).expect("Failed to list todo").len(),
+            3
+        );
+
+        // A bad line adds none of them
+        let tasks = parse_task_lines("Fine\nprio:high\n");
+        assert!(Todo::add_all(&mut conn, &tasks, false).is_err());
+        assert_eq!(
+            Todo::list(&conn, false).expect("Failed to list todo").len(),
+            3
+        );
+
+        // Forcing lets a long name through, as with add --force
+        let tasks = parse_task_lines(&"x".repeat(MAX_NAME_LEN + 1));
+        assert!(Todo::add_all(&mut conn, &tasks, false).is_err());
+        let added = Todo::add_all(&mut conn, &tasks, true).expect("--force was refused");
+        assert_eq!(added[0].name.len(), MAX_NAME_LEN + 1);
+    }
+
+    #[test]
+    fn test_dedupe_tasks() {
+        let tasks = dedupe_tasks(parse_task_lines(
+            "Buy milk\nbuy MILK +home\nPay rent\nBuy milk\n",
+        ));
+        let names: Vec<&str> = tasks.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(names, ["Buy milk", "Pay rent"]);
+        assert!(tasks[0].tags.is_empty(), "The first one was not kept");
+    }
 
-    input.to_string()
-}
+    #[test]
+    fn test_parse_task_input() {
+        let task = parse_task_input("prio:high Pay +home rent due:2024-05-01 +bills");
+        assert_eq!(
+            task,
+            ParsedTask {
+                name: "Pay rent".to_string(),
+                due_date: Some("2024-05-01".to_string()),
+                priority: Some("high".to_string()),
+                tags: vec!["home".to_string(), "bills".to_string()],
+            }
+        );
 
-// Returns a connection, creating the database if needed
-pub fn get_connection() -> Result<Connection> {
-    let db_folder = get_home() + "/" + "todo_db/";
-    let db_file_path = db_folder.clone() + "todo.sqlite";
-    verify_db_path(&db_folder)?;
-    let conn = Connection::open(db_file_path)?;
-    verify_db(&conn)?;
-    Ok(conn)
-}
+        let task = parse_task_input("Just a name");
+        assert_eq!(task.name, "Just a name");
+        assert_eq!(task.due_date, None);
+        assert_eq!(task.priority, None);
+        assert!(task.tags.is_empty());
 
-// Aux function that creates tables if they don't exist
-pub fn verify_db(conn: &Connection) -> Result<()> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS todo (
-    	id	        INTEGER NOT NULL,
-    	name	    TEXT NOT NULL,
-    	date_added	REAL NOT NULL DEFAULT current_timestamp,
-    	is_done	    NUMERIC NOT NULL DEFAULT 0,
-    	    PRIMARY KEY(id AUTOINCREMENT)
-    )",
-        [], // no params for this query
-    )?;
-    Ok(())
-}
+        // Invalid values and escaped tokens are kept in the name
+        let task = parse_task_input("Read due:someday prio:urgent \\+1 ratio 16\\:9 + done");
+        assert_eq!(
+            task.name,
+            "Read due:someday prio:urgent +1 ratio 16:9 + done"
+        );
+        assert_eq!(
+            task,
+            ParsedTask {
+                name: task.name.clone(),
+                ..Default::default()
+            }
+        );
 
-// Aux function that creates the folder where the DB should be stored
-// if it doesn't exist
-pub fn verify_db_path(db_folder: &str) -> Result<()> {
-    if !Path::new(db_folder).exists() {
-        // Check if the folder doesn't exist
-        match fs::create_dir(db_folder) {
-            Ok(_) => println!("Folder '{}' created.", db_folder),
-            Err(e) => eprintln!("Error creating folder: {}", e),
-        }
+        let task = parse_task_input("Escaped due\\:2024-05-01 prio:L");
+        assert_eq!(task.name, "Escaped due:2024-05-01");
+        assert_eq!(task.priority.as_deref(), Some("low"));
     }
-    Ok(())
-}
 
-// Prints help with a list of commands and parameters
-pub fn help() -> Result<()> {
-    let help_title = "\nAvailable commands:";
-    let help_text = r#"    
-        - add [TASK]
-            Ads new task/s
-            Example: todo add "Build a tree"
+    #[test]
+    fn test_add_parsed() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
 
-        - list
-            Lists all tasks
-            Example: todo list
+        let task = parse_task_input("Pay rent due:2024-05-01 prio:med +home +bills");
+        Todo::add_parsed(&conn, &task, None, false).expect("Failed to add todo");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
 
-        - toggle [ID]
-            Toggles the status of a task (Done/Pending)
-            Example: todo toggle 2
-        
-        - rm [ID]
-            Removes a task
-            Example: todo rm 4
-        
-        - sort
-            Sorts completed and uncompleted tasks
-        
-        - reset
-            Deletes all tasks
-        "#;
+        assert_eq!(todos[0].name, "Pay rent");
+        assert_eq!(todos[0].due_date.as_deref(), Some("2024-05-01"));
+        assert_eq!(todos[0].priority.as_deref(), Some("medium"));
+        assert_eq!(todos[0].tags, vec!["home".to_string(), "bills".to_string()]);
 
-    println!("{}", style(help_title).cyan().bright());
-    println!("{}", style(help_text).green());
-    Ok(())
-}
+        let invalid = ParsedTask {
+            name: "Bad priority".to_string(),
+            priority: Some("urgent".to_string()),
+            ..Default::default()
+        };
+        assert!(Todo::add_parsed(&conn, &invalid, None, false).is_err());
+    }
 
-// Get the user's home directory for each platform
-fn get_home() -> String {
-    let home_dir = match env::var("HOME") {
-        Ok(path) => PathBuf::from(path),
-        Err(_) => {
-            // Fallback for Windows and macOS
-            if cfg!(target_os = "windows") {
-                if let Some(userprofile) = env::var("USERPROFILE").ok() {
-                    PathBuf::from(userprofile)
-                } else if let Some(homedrive) = env::var("HOMEDRIVE").ok() {
-                    let homepath = env::var("HOMEPATH").unwrap_or("".to_string());
-                    PathBuf::from(format!("{}{}", homedrive, homepath))
-                } else {
-                    panic!("Could not determine the user's home directory.");
-                }
-            } else if cfg!(target_os = "macos") {
-                let home = env::var("HOME").unwrap_or("".to_string());
-                PathBuf::from(home)
-            } else {
-                panic!("Could not determine the user's home directory.");
+    #[test]
+    fn test_verify_db_path_reports_errors() {
+        let temp_dir = tempfile::tempdir().expect("Could not create a temp dir");
+        let file = temp_dir.path().join("not-a-folder");
+        fs::write(&file, "").expect("Could not create a file");
+
+        let db_folder = file.join("todo_db");
+        let result = verify_db_path(&db_folder.to_string_lossy());
+        assert!(
+            matches!(result, Err(TodoError::Io(_))),
+            "Creating a folder inside a file did not fail: {:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_verify_db_path_creates_parents() {
+        let temp_dir = tempfile::tempdir().expect("Could not create a temp dir");
+        let db_folder = temp_dir.path().join(".local").join("state").join("todo");
+
+        verify_db_path(&db_folder.to_string_lossy()).expect("Could not create the folders");
+        assert!(db_folder.is_dir(), "The folder was not created");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for dir in [temp_dir.path().join(".local"), db_folder.clone()] {
+                let mode = fs::metadata(&dir)
+                    .expect("Could not read the folder")
+                    .permissions()
+                    .mode();
+                assert_eq!(
+                    mode & 0o777,
+                    0o700,
+                    "{} is readable by others",
+                    dir.display()
+                );
             }
         }
-    };
 
-    // Convert the PathBuf to a &str
-    match home_dir.to_str() {
-        Some(home_str) => home_str.to_string(),
-        None => panic!("Failed to convert home directory to a string."),
+        // An existing folder is fine, and a database can be made in it
+        verify_db_path(&db_folder.to_string_lossy()).expect("An existing folder failed");
+        get_connection_at(&db_folder.join("todo.sqlite")).expect("Could not open the db");
     }
-}
 
-/* -----------------------------------------------------------
- *    Tests
- * ---------------------------------------------------------*/
+    #[test]
+    fn test_unwritable_folder_is_explained() {
+        let temp_dir = tempfile::tempdir().expect("Could not create a temp dir");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use lazy_static::lazy_static;
-    use std::sync::Mutex;
+        // A folder where the file should be fails the same way for everyone
+        let db_path = temp_dir.path().join("todo.sqlite");
+        fs::create_dir(&db_path).expect("Could not create a folder");
+        let error = get_connection_at(&db_path).expect_err("Opened a folder as a database");
+        assert!(matches!(error, TodoError::Io(_)), "{:?}", error);
+        assert!(error.to_string().contains(&db_path.display().to_string()));
 
-    // Creates a persistant in memory db connection
-    // creates tables if necessary
-    lazy_static! {
-        static ref DATABASE_CONNECTION: Mutex<Connection> = {
-            let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
-            verify_db(&conn).expect("Cannot create tables");
-            Mutex::new(conn)
-        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let read_only = temp_dir.path().join("read-only");
+            fs::create_dir(&read_only).expect("Could not create a folder");
+            fs::set_permissions(&read_only, fs::Permissions::from_mode(0o500))
+                .expect("Could not make the folder read only");
+            // root can write anywhere, so there's nothing to check then
+            if fs::write(read_only.join("probe"), "").is_err() {
+                let db_path = read_only.join("todo.sqlite");
+                let error = get_connection_at(&db_path).expect_err("Wrote to a read only folder");
+                let message = error.to_string();
+                assert!(matches!(error, TodoError::Io(_)), "{:?}", error);
+                assert!(
+                    message.contains(&db_path.display().to_string()),
+                    "{}",
+                    message
+                );
+                assert!(message.contains("write"), "{}", message);
+            }
+            fs::set_permissions(&read_only, fs::Permissions::from_mode(0o700))
+                .expect("Could not restore the permissions");
+        }
     }
 
-    fn reset_db(conn: &Connection) -> Result<()> {
-        conn.execute("DELETE FROM todo", ())?;
-        Ok(())
+    // An environment variable lookup that only knows the given variables
+    fn vars(pairs: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| {
+            pairs
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        }
     }
 
-    fn contains_task(todos: &Vec<Todo>, target_name: &str) -> bool {
-        for todo in todos {
-            if todo.name == target_name {
-                return true;
-            }
-        }
-        false
+    #[test]
+    fn test_resolve_db_path() {
+        let resolve = |db_override, pairs| {
+            resolve_db_path(db_override, vars(pairs)).expect("Could not resolve the path")
+        };
+        let db_path = PathBuf::from("/tmp/todo-test/todo.sqlite");
+        let state = &[("XDG_STATE_HOME", "/tmp/todo-test-state")];
+        let with_env = &[
+            ("XDG_STATE_HOME", "/tmp/todo-test-state"),
+            ("TODO_DB_PATH", "/tmp/todo-test/todo.sqlite"),
+        ];
+
+        assert_eq!(resolve(Some("/tmp/todo-test/todo.sqlite"), state), db_path);
+        assert_eq!(resolve(None, with_env), db_path, "TODO_DB_PATH was ignored");
+        assert_eq!(
+            resolve(Some("/tmp/other.sqlite"), with_env),
+            PathBuf::from("/tmp/other.sqlite"),
+            "TODO_DB_PATH won over the override"
+        );
+        assert_eq!(
+            resolve(None, state),
+            PathBuf::from("/tmp/todo-test-state/todo/todo.sqlite")
+        );
     }
 
     #[test]
-    fn test_add_todo() {
-        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
-        reset_db(&conn).expect("Messed up resetting the db");
+    fn test_data_dir() {
+        let dir = |os, pairs| data_dir(os, vars(pairs)).expect("No data dir");
 
-        // Call the add function to add a todo
-        let name = "Test Todo";
-        Todo::add(&conn, name).expect("Failed to add todo");
+        assert_eq!(
+            dir("linux", &[("HOME", "/home/ann")]),
+            PathBuf::from("/home/ann/.local/state/todo")
+        );
+        assert_eq!(
+            dir(
+                "linux",
+                &[("HOME", "/home/ann"), ("XDG_STATE_HOME", "/var/ann")]
+            ),
+            PathBuf::from("/var/ann/todo")
+        );
+        assert_eq!(
+            dir(
+                "linux",
+                &[("HOME", "/home/ann"), ("XDG_STATE_HOME", "relative")]
+            ),
+            PathBuf::from("/home/ann/.local/state/todo"),
+            "A relative XDG_STATE_HOME was used"
+        );
+        assert_eq!(
+            dir("macos", &[("HOME", "/Users/ann")]),
+            PathBuf::from("/Users/ann/Library/Application Support/todo")
+        );
+        assert_eq!(
+            dir("windows", &[("LOCALAPPDATA", "C:/Users/ann/AppData/Local")]),
+            PathBuf::from("C:/Users/ann/AppData/Local/todo")
+        );
+        // Windows without HOME still finds the profile folder
+        assert_eq!(
+            home_dir("windows", vars(&[("USERPROFILE", "C:/Users/ann")]))
+                .expect("No home on Windows"),
+            PathBuf::from("C:/Users/ann")
+        );
+        assert_eq!(
+            home_dir(
+                "windows",
+                vars(&[("HOMEDRIVE", "D:"), ("HOMEPATH", "/ann")])
+            )
+            .expect("No home on Windows"),
+            PathBuf::from("D:/ann")
+        );
+        assert!(matches!(
+            data_dir("linux", vars(&[])),
+            Err(TodoError::Home(_))
+        ));
+    }
 
-        // Query the database to check if the todo was added
-        let mut stmt = conn
-            .prepare("SELECT COUNT(*) FROM todo WHERE name = ?")
-            .expect("Failed to prepare statement");
-        let count: i32 = stmt
-            .query_row(&[name], |row| row.get(0))
-            .expect("Failed to query database");
+    #[test]
+    fn test_move_legacy_db() {
+        let home = tempfile::tempdir().expect("Could not create a temp dir");
+        let legacy_path = home.path().join("todo_db").join("todo.sqlite");
+        let db_path = home.path().join(".local/state/todo/todo.sqlite");
+        let conn = get_connection_at(&legacy_path).expect("Could not open the db");
+        Todo::add(&conn, "Survives the move").expect("Could not add todo");
+        drop(conn);
 
-        assert_eq!(count, 1, "Todo was not added to the database");
+        assert!(move_legacy_db(&legacy_path, &db_path).expect("Could not move the db"));
+        assert!(!legacy_path.exists(), "The old database was left behind");
+        assert!(
+            !legacy_path.parent().unwrap().exists(),
+            "The empty old folder was left behind"
+        );
+        let conn = get_connection_at(&db_path).expect("Could not open the moved db");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        assert_eq!(todos[0].name, "Survives the move");
+
+        // Nothing happens once the new database exists
+        fs::create_dir_all(legacy_path.parent().unwrap()).expect("Could not create folder");
+        fs::write(&legacy_path, "").expect("Could not create a file");
+        assert!(!move_legacy_db(&legacy_path, &db_path).expect("Could not check the db"));
+        assert!(legacy_path.exists());
     }
 
     #[test]
-    fn test_list_todo() {
+    fn test_clear_completed_todo() {
         let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
         reset_db(&conn).expect("Messed up resetting the db");
 
+        assert_eq!(
+            Todo::clear_completed(&conn).expect("Failed to clear todos"),
+            0,
+            "clear_completed() deleted something from an empty list"
+        );
+
         Todo::add(&conn, "Task 1").expect("Could not add todo");
         Todo::add(&conn, "Task 2").expect("Could not add todo");
-        Todo::add(&conn, "Task 3").expect("Could not add todo");
         let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        Todo::toggle(&conn, todos[0].id).expect("Could not toggle first todo");
 
-        assert_eq!(
-            todos.len(),
-            3,
-            "Wrong number of todo items returned by list()"
-        );
+        let deleted = Todo::clear_completed(&conn).expect("Failed to clear todos");
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+
+        assert_eq!(deleted, 1, "Wrong number of todos cleared");
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0].name, "Task 2", "A pending task was cleared");
     }
 
     #[test]
-    fn test_sort_todo() {
+    fn test_rename_todo() {
         let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
         reset_db(&conn).expect("Messed up resetting the db");
 
         Todo::add(&conn, "Task 1").expect("Could not add todo");
-        Todo::add(&conn, "Task 2").expect("Could not add todo");
-        Todo::add(&conn, "Task 3").expect("Could not add todo");
-        let todos = Todo::list(&conn, false).expect("Failed to list todo");
-        // toggles the first entry
-        Todo::toggle(&conn, todos[0].id).expect("Could not toggle first todo");
+        let id = Todo::list(&conn, false).expect("Failed to list todo")[0].id;
+        Todo::rename(&conn, id, "Renamed").expect("Could not rename todo");
 
-        // true means sorted by status
-        let todos = Todo::list(&conn, true).expect("Failed to sort todos");
+        let todo = Todo::get(&conn, id).expect("Failed to get todo");
+        assert_eq!(todo.name, "Renamed", "Task was not renamed");
+    }
 
-        assert_eq!(
-            todos[2].name, "Task 1",
-            "The todo marked as done was not the LAST one returned"
+    #[test]
+    fn test_vacuum() {
+        let db_folder = tempfile::tempdir().expect("Could not create a temp dir");
+        let conn = get_connection_at(&db_folder.path().join("todo.sqlite"))
+            .expect("Could not open the db");
+        let long_name = "x".repeat(MAX_NAME_LEN);
+        for _ in 0..200 {
+            Todo::add(&conn, &long_name).expect("Could not add todo");
+        }
+        Todo::reset(&conn).expect("Could not reset the db");
+
+        let (before, after) = vacuum(&conn).expect("Could not vacuum the db");
+        assert!(
+            after < before,
+            "Vacuum reclaimed nothing ({} -> {})",
+            before,
+            after
         );
 
-        assert_eq!(
-            todos.len(),
-            3,
-            "Wrong number of todo items returned by sort()"
+        conn.execute_batch("BEGIN")
+            .expect("Could not begin a transaction");
+        assert!(vacuum(&conn).is_err(), "Vacuum ran inside a transaction");
+    }
+
+    #[test]
+    fn test_backup() {
+        let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        verify_db(&conn).expect("Cannot create tables");
+        Todo::add(&conn, "Backed up").expect("Could not add todo");
+
+        let backup_folder = tempfile::tempdir().expect("Could not create a temp dir");
+        let backup_path = backup(&conn, backup_folder.path()).expect("Could not back up the db");
+        let file_name = backup_path.file_name().unwrap().to_string_lossy();
+        assert!(
+            file_name.starts_with("todo-") && file_name.ends_with(".sqlite"),
+            "Unexpected backup name {}",
+            file_name
         );
+
+        let restored = Connection::open(&backup_path).expect("Could not open the backup");
+        let todos = Todo::list(&restored, false).expect("Failed to list todo");
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0].name, "Backed up");
     }
 
     #[test]
-    fn test_rm_todo() {
+    fn test_verify_db_creates_indexes() {
         let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
-        reset_db(&conn).expect("Messed up resetting the db");
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'todo'")
+            .expect("Could not query sqlite_master");
+        let indexes: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .expect("Could not list indexes")
+            .collect::<rusqlite::Result<_>>()
+            .expect("Could not read index names");
 
-        Todo::add(&conn, "Task 1").expect("Could not add todo");
-        Todo::add(&conn, "Task 2").expect("Could not add todo");
-        Todo::add(&conn, "Task 3").expect("Could not add todo");
-        let todos = Todo::list(&conn, false).expect("Failed to list todo");
-        // toggles the first entry
-        Todo::rm(&conn, todos[0].id).expect("Could not remove first todo");
+        assert!(
+            indexes.contains(&"todo_is_done".to_string()),
+            "{:?}",
+            indexes
+        );
+        assert!(
+            indexes.contains(&"todo_date_added".to_string()),
+            "{:?}",
+            indexes
+        );
+    }
 
-        // true means sorted by status
-        let todos = Todo::list(&conn, false).expect("Failed to list todos");
-        dbg!(&todos);
+    // A UTC time in the stored format
+    fn utc(datetime: &str) -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str(datetime, STORED_DATETIME)
+            .expect("Bad test datetime")
+            .and_utc()
+    }
+
+    // Two tasks covering quoting, optional fields and tags
+    fn sample_todos() -> Vec<Todo> {
+        let mut pay = Todo::new(
+            1,
+            "Pay \"rent\", now".to_string(),
+            utc("2024-05-01 10:00:00"),
+            0,
+        );
+        pay.due_date = Some("2024-05-02".to_string());
+        pay.priority = Some("high".to_string());
+        pay.tags = vec!["home".to_string(), "bills".to_string()];
+        pay.color = Some("red".to_string());
+        let mut child = Todo::new(2, "Tab\there".to_string(), utc("2024-05-01 11:00:00"), 1);
+        child.parent_id = Some(1);
+        vec![pay, child]
+    }
+
+    #[test]
+    fn test_render_table() {
+        let output = render_table(sample_todos(), 20, StatusGlyphs::Unicode);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines.len(),
+            3,
+            "Expected 2 rows and 1 details line: {:?}",
+            lines
+        );
+        assert!(lines[0].contains("Pay \"rent\", now") && lines[0].contains("○"));
+        assert!(lines[1].contains("due 2024-05-02") && lines[1].contains("+bills"));
+        assert!(lines[2].contains("  Tab\there"), "Subtask is not indented");
+        assert!(lines[2].contains("✓"));
+    }
+
+    #[test]
+    fn test_render_table_ascii() {
+        let output = render_table(sample_todos(), 20, StatusGlyphs::Ascii);
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].contains("[ ]"), "No pending mark: {:?}", lines[0]);
+        assert!(lines[2].contains("[x]"), "No done mark: {:?}", lines[2]);
+        assert!(
+            output.is_ascii(),
+            "The ascii table has Unicode in it: {}",
+            output
+        );
+    }
 
+    #[test]
+    fn test_render_plain() {
+        let output = render_plain(&sample_todos());
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert_eq!(line.split('\t').count(), RENDERED_COLUMNS.len());
+        }
         assert_eq!(
-            todos.len(),
-            2,
-            "Wrong number of todo items returned by sort()"
+            lines[1], "2\tTab here\t2024-05-01 11:00:00\t1\t1\t\t\t\t\t",
+            "Tabs in names must not add fields"
         );
+    }
 
+    #[test]
+    fn test_render_csv() {
+        let output = render_csv(&sample_todos());
+        let lines: Vec<&str> = output.lines().collect();
         assert_eq!(
-            contains_task(&todos, "Task 1"),
-            false,
-            "Task 1 was not deleted!"
+            lines[0],
+            "id,name,date_added,is_done,parent_id,due_date,priority,tags,list,color"
+        );
+        assert_eq!(
+            lines[1],
+            "1,\"Pay \"\"rent\"\", now\",2024-05-01 10:00:00,0,,2024-05-02,high,home bills,,red"
         );
+        assert_eq!(lines[2], "2,Tab\there,2024-05-01 11:00:00,1,1,,,,,");
     }
 
     #[test]
-    fn test_toggle_todo() {
+    fn test_render_json() {
+        let output = render_json(&sample_todos());
+        assert!(output.starts_with("[{") && output.trim_end().ends_with("}]"));
+        assert!(
+            output.contains(r#""name":"Pay \"rent\", now""#),
+            "{}",
+            output
+        );
+        assert!(output.contains(r#""tags":["home","bills"]"#), "{}", output);
+        assert!(output.contains(r#""name":"Tab\there","date_added":"2024-05-01T11:00:00Z","completed":true,"parent_id":1,"due_date":null,"priority":null,"tags":[],"list":null,"color":null"#), "{}", output);
+        assert_eq!(render_json(&[]), "[]\n");
+
+        // It reads back as the same tasks
+        let back: Vec<Todo> = serde_json::from_str(&output).expect("Could not deserialize");
+        assert_eq!(back, sample_todos());
+    }
+
+    #[test]
+    fn test_toggle_returns_new_status() {
         let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
         reset_db(&conn).expect("Messed up resetting the db");
 
-        Todo::add(&conn, "Task 1").expect("Could not add todo");
-        Todo::add(&conn, "Task 2").expect("Could not add todo");
-        let todos = Todo::list(&conn, false).expect("Failed to list todo");
-        // toggles the first entry
-        Todo::toggle(&conn, todos[0].id).expect("Could not toggle first todo");
+        let todo = Todo::add(&conn, "Task 1").expect("Could not add todo");
+        assert!(
+            Todo::toggle(&conn, todo.id).expect("Could not toggle"),
+            "Not marked done"
+        );
+        assert!(
+            !Todo::toggle(&conn, todo.id).expect("Could not toggle"),
+            "Not marked pending"
+        );
+        assert!(matches!(
+            Todo::toggle(&conn, todo.id + 1),
+            Err(TodoError::NotFound(id)) if id == todo.id + 1
+        ));
+    }
 
-        // true means sorted by status
-        let todos = Todo::list(&conn, false).expect("Failed to sort todos");
-        dbg!(&todos);
+    #[test]
+    fn test_missing_id_is_reported() {
+        let conn = DATABASE_CONNECTION.lock().expect("Mutex lock failed");
+        reset_db(&conn).expect("Messed up resetting the db");
+
+        let missing = 9999;
+        assert!(
+            matches!(Todo::toggle(&conn, missing), Err(TodoError::NotFound(9999))),
+            "toggle() succeeded on a missing id"
+        );
+        assert!(
+            matches!(Todo::rm(&conn, missing), Err(TodoError::NotFound(9999))),
+            "rm() succeeded on a missing id"
+        );
+        assert!(
+            matches!(
+                Todo::rename(&conn, missing, "Name"),
+                Err(TodoError::NotFound(9999))
+            ),
+            "rename() succeeded on a missing id"
+        );
+    }
 
+    #[test]
+    fn test_name_column_width() {
+        let unicode = StatusGlyphs::Unicode;
         assert_eq!(
-            todos.len(),
-            2,
-            "Wrong number of todo items returned by toggle()"
+            name_column_width(None, unicode),
+            51,
+            "Wrong width when not a TTY"
+        );
+        assert_eq!(name_column_width(Some(80), unicode), 51);
+        assert_eq!(
+            name_column_width(Some(80), StatusGlyphs::Ascii),
+            49,
+            "The wider ascii marks were not accounted for"
+        );
+        assert_eq!(
+            name_column_width(Some(129), unicode),
+            100,
+            "Wide terminals are wasted"
+        );
+        assert_eq!(
+            name_column_width(Some(20), unicode),
+            10,
+            "Narrow terminals underflow"
         );
+    }
 
-        // True and False are stored as 0  or 1 in the db
-        assert_eq!(todos[0].is_done, 1, "Task 1 was not toggled!");
+    #[test]
+    fn test_truncate_at() {
+        assert_eq!(truncate_at("Buy milk", 8), "Buy milk");
+        assert_eq!(truncate_at("Buy milk", 5), "Buy …");
+        // Short limits and empty strings don't underflow
+        assert_eq!(truncate_at("Buy milk", 2), "B…");
+        assert_eq!(truncate_at("Buy milk", 1), "…");
+        assert_eq!(truncate_at("Buy milk", 0), "");
+        assert_eq!(truncate_at("", 0), "");
+
+        // Cuts between characters, not in the middle of their bytes
+        assert_eq!(truncate_at("Café crème", 5), "Café…");
+        assert_eq!(truncate_at("Ship 🚀🚀🚀", 8), "Ship 🚀…");
+        // Combining marks take no cell and stay with their letter
+        assert_eq!(truncate_at("Cafe\u{301} time", 5), "Cafe\u{301}…");
+        assert_eq!(truncate_at("Cafe\u{301}", 4), "Cafe\u{301}");
+        // CJK characters take two cells each
+        assert_eq!(truncate_at("买牛奶和面包", 12), "买牛奶和面包");
+        assert_eq!(truncate_at("买牛奶和面包", 7), "买牛奶…");
+        assert_eq!(truncate_at("买牛奶和面包", 6), "买牛…");
+        assert_eq!(truncate_at("买牛奶和面包", 2), "…");
+    }
+
+    #[test]
+    fn test_render_table_aligns_wide_names() {
+        let todos = vec![
+            Todo::new(1, "Buy milk".to_string(), utc("2024-05-01 10:00:00"), 0),
+            Todo::new(2, "买牛奶和面包".to_string(), utc("2024-05-01 10:00:00"), 0),
+            Todo::new(
+                3,
+                "Ship 🚀 today".to_string(),
+                utc("2024-05-01 10:00:00"),
+                0,
+            ),
+            Todo::new(4, "Cafe\u{301}".to_string(), utc("2024-05-01 10:00:00"), 0),
+            Todo::new(
+                5,
+                "很长的任务名称超过了列宽".to_string(),
+                utc("2024-05-01 10:00:00"),
+                0,
+            ),
+        ];
+        let output =
+            console::strip_ansi_codes(&render_table(todos, 12, StatusGlyphs::Unicode)).to_string();
+        let columns: Vec<usize> = output
+            .lines()
+            .map(|line| line[..line.find('○').expect("No status mark")].width())
+            .collect();
+        assert_eq!(columns, [20; 5], "Misaligned rows:\n{}", output);
+        assert!(output.contains("很长的任务…"), "Not truncated:\n{}", output);
     }
 
     #[test]
@@ -1,95 +1,631 @@
 extern crate todo;
-use console::style;
-use dialoguer::Confirm;
-use rusqlite::Result;
+use console::{style, user_attended_stderr, Term};
+use rusqlite::{Connection, OpenFlags};
 use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
 
 use todo::*;
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+// Exit codes scripts can branch on, documented in help()
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_DB: i32 = 3;
+
+fn main() {
+    if let Err(error) = run() {
+        let message = match &error {
+            error if is_busy(error) => {
+                "The database is in use by another todo process, try again in a moment".to_string()
+            }
+            TodoError::Db(error) => format!("Database error: {}", error),
+            error => error.to_string(),
+        };
+        eprintln!("{}", style(message).red());
+        std::process::exit(exit_code(&error));
+    }
+}
+
+// Picks the exit code matching a library error
+fn exit_code(error: &TodoError) -> i32 {
+    match error {
+        TodoError::NotFound(_) => EXIT_NOT_FOUND,
+        TodoError::Validation(_) => EXIT_USAGE,
+        TodoError::Db(_) | TodoError::Io(_) | TodoError::Home(_) | TodoError::SchemaTooNew(..) => {
+            EXIT_DB
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let mut args: Vec<String> = env::args().collect();
+    let db_override = take_flag_value(&mut args, "--db");
+    let verbose = take_flag(&mut args, &["--verbose", "-v"]);
+    let glyphs = if take_flag(&mut args, &["--ascii"]) {
+        StatusGlyphs::Ascii
+    } else {
+        StatusGlyphs::Unicode
+    };
+
+    // The demo doesn't need (or create) a database
+    if args.get(1).map(String::as_str) == Some("demo") {
+        return demo(glyphs);
+    }
 
     // Get a connection to the DB
-    let conn = get_connection()?;
+    let db_path = resolve_db_path(db_override.as_deref(), env_var)?;
+    // The doctor looks at the database as it is, before any migration
+    if args.get(1).map(String::as_str) == Some("doctor") {
+        return doctor(&db_path);
+    }
+    let mut conn = get_connection_at(&db_path)?;
+    if verbose {
+        log_sql(&mut conn);
+    }
 
     if args.len() == 1 {
         help()?;
-        std::process::exit(1);
+        std::process::exit(EXIT_USAGE);
     }
 
     let command = &args[1];
-    let suffix = &args[2..].iter().cloned().collect::<Vec<_>>().join(" ");
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
 
     match command.as_str() {
+        "add"
+            if args.get(2).is_some_and(|arg| arg == "-")
+                || args.iter().any(|arg| arg == "--stdin") =>
+        {
+            let words = without_flags(
+                &args[2..],
+                &["--stdin", "--force", "--allow-duplicates"],
+                &[],
+            );
+            if words.iter().any(|word| *word != "-") {
+                usage("add - [--force] [--allow-duplicates]");
+            }
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            let mut tasks = parse_task_lines(&input);
+            if !args.iter().any(|arg| arg == "--allow-duplicates") {
+                tasks = dedupe_tasks(tasks);
+                for task in &tasks {
+                    if let Some(id) = Todo::find_duplicate(&conn, &task.name)? {
+                        eprintln!(
+                            "'{}' already exists as task {} (use --allow-duplicates to add it anyway)",
+                            task.name, id
+                        );
+                        std::process::exit(EXIT_USAGE);
+                    }
+                }
+            }
+            let force = args.iter().any(|arg| arg == "--force");
+            let added = Todo::add_all(&mut conn, &tasks, force)?;
+            println!("Added {} tasks", added.len());
+            Ok(())
+        }
         "add" => {
-            if suffix.as_str().is_empty() {
-                help()?;
-                std::process::exit(1);
+            let name =
+                without_flags(&args[2..], &["--force", "--allow-duplicates"], &["--at"]).join(" ");
+            if name.is_empty() {
+                usage("add [TASK]");
+            }
+            let task = parse_task_input(&name);
+            if !args.iter().any(|arg| arg == "--allow-duplicates") {
+                if let Some(id) = Todo::find_duplicate(&conn, &task.name)? {
+                    eprintln!(
+                        "'{}' already exists as task {} (use --allow-duplicates to add it anyway)",
+                        task.name, id
+                    );
+                    std::process::exit(EXIT_USAGE);
+                }
+            }
+            let force = args.iter().any(|arg| arg == "--force");
+            let todo = Todo::add_parsed(&conn, &task, flag_value(&args, "--at"), force)?;
+            println!("added #{}: {}", todo.id, todo.name);
+            Ok(())
+        }
+        "sub" => {
+            if args.len() < 4 {
+                usage("sub [PARENT_ID] [TASK]");
             } else {
-                Todo::add(&conn, suffix.as_str())?;
+                let parent_id = parse_id(&args[2]);
+                let name = args[3..].join(" ");
+                let todo = Todo::add_subtask(&conn, parent_id, &name)?;
+                println!("added #{} under #{}: {}", todo.id, parent_id, todo.name);
             }
             Ok(())
         }
         "list" => {
-            println!("TODO List (sorted by id):");
-            let todos = Todo::list(&conn, false)?;
-            Todo::print_list(todos)?;
+            let offset = usize_flag(&args, "--offset").unwrap_or(0);
+            let limit = usize_flag(&args, "--limit");
+            let status = if args.iter().any(|arg| arg == "--pending") {
+                Some(false)
+            } else if args
+                .iter()
+                .any(|arg| arg == "--done" || arg == "--completed")
+            {
+                Some(true)
+            } else {
+                None
+            };
+            let sort = match flag_value(&args, "--sort") {
+                Some(sort) => ListSort::parse(sort).unwrap_or_else(|| {
+                    eprintln!("Unknown sort '{}'", sort);
+                    std::process::exit(EXIT_USAGE);
+                }),
+                None => ListSort::Id,
+            };
+            let filter = ListFilter {
+                offset,
+                limit,
+                status,
+                search: flag_value(&args, "--search").map(str::to_string),
+                list: flag_value(&args, "--list").map(str::to_string),
+                since: flag_value(&args, "--since").map(str::to_string),
+                until: flag_value(&args, "--until").map(str::to_string),
+                sort,
+            };
+            let mut todos = Todo::list_page(&conn, &filter)?;
+            let format = match flag_value(&args, "--format") {
+                Some(format) => ListFormat::parse(format).unwrap_or_else(|| {
+                    eprintln!("Unknown list format '{}'", format);
+                    std::process::exit(EXIT_USAGE);
+                }),
+                None => ListFormat::Table,
+            };
+            let tree = args.iter().any(|arg| arg == "--tree");
+            if tree {
+                todos = Todo::tree_order(todos);
+            }
+            // Only the table gets a title, the other formats are for programs
+            if format == ListFormat::Table {
+                if tree {
+                    println!("TODO List (grouped by parent):");
+                } else if args.iter().any(|arg| arg == "--sort") {
+                    println!("TODO List:");
+                } else {
+                    println!("TODO List (sorted by id):");
+                }
+            }
+            print!("{}", Todo::render(todos, format, glyphs));
+            Ok(())
+        }
+        "recent" => {
+            let n = match args.get(2) {
+                Some(n) => n.parse::<usize>().unwrap_or_else(|_| usage("recent [N]")),
+                None => 5,
+            };
+            println!("TODO List (most recent first):");
+            Todo::print_list(Todo::latest(&conn, n)?, glyphs)?;
+            Ok(())
+        }
+        "stats" => {
+            let stats = Todo::stats(&conn)?;
+            println!(
+                "{} pending, {} done, {} total ({:.0}% done)",
+                stats.pending,
+                stats.done,
+                stats.total,
+                stats.completion_rate() * 100.0
+            );
+            Ok(())
+        }
+        "show" => {
+            if args.len() < 3 {
+                usage("show [ID]");
+            } else {
+                let id = resolve_id(&conn, &without_flags(&args[2..], &[], &[]).join(" "))?;
+                let todo = Todo::get(&conn, id)?;
+                Todo::print_list(vec![todo], glyphs)?;
+            }
             Ok(())
         }
         "toggle" => {
             if args.len() < 3 {
-                help()?;
-                std::process::exit(1);
+                usage("toggle [ID]");
             } else {
-                let id = args[2].parse::<i32>().unwrap();
-                Todo::toggle(&conn, id)?;
-                println!("Toggled task with ID: {}", id);
+                let id = resolve_id(&conn, &without_flags(&args[2..], &[], &[]).join(" "))?;
+                let status = if Todo::toggle(&conn, id)? {
+                    "Done"
+                } else {
+                    "Pending"
+                };
+                println!("task {} marked {}", id, status);
             }
             Ok(())
         }
-        "reset" => {
-            let confirmation = Confirm::new()
-                .with_prompt(
-                    style("Do you want REALLY want to reset?")
-                        .bright()
-                        .red()
-                        .to_string(),
-                )
-                .interact();
-
-            match confirmation {
-                Ok(c) => {
-                    if c {
-                        Todo::reset(&conn)?;
-                        println!("Database reset. All tasks cleared.");
-                    } else {
-                        println!("Alright. No reset!");
-                    }
+        "rename" => {
+            if args.len() < 4 {
+                usage("rename [ID] [NAME]");
+            } else {
+                let id = parse_id(&args[2]);
+                let name = args[3..].join(" ");
+                Todo::rename(&conn, id, &name)?;
+                println!("Renamed task with ID: {}", id);
+            }
+            Ok(())
+        }
+        "due" => {
+            if args.len() < 4 {
+                usage("due [ID] [DATE]");
+            } else {
+                let id = parse_id(&args[2]);
+                Todo::set_due(&conn, id, &args[3])?;
+                println!("Task {} is due on {}", id, args[3]);
+            }
+            Ok(())
+        }
+        "move-list" | "reassign" => {
+            if args.len() < 4 {
+                usage("move-list [ID] [LIST]");
+            }
+            let id = parse_id(&args[2]);
+            let list = args[3..].join(" ");
+            Todo::move_to_list(&conn, id, &list)?;
+            println!("Task {} is in the list {}", id, list.trim());
+            Ok(())
+        }
+        "color" => {
+            if args.len() < 4 {
+                usage("color [ID] [red|yellow|green|blue|none]");
+            }
+            let id = parse_id(&args[2]);
+            let color = Some(args[3].as_str()).filter(|color| *color != "none");
+            Todo::set_color(&conn, id, color)?;
+            match color {
+                Some(color) => println!("Task {} is labeled {}", id, color),
+                None => println!("Task {} has no label", id),
+            }
+            Ok(())
+        }
+        "snooze" | "defer" => {
+            if args.len() < 3 {
+                usage("snooze [ID] [DAYS]");
+            }
+            let id = parse_id(&args[2]);
+            let days = match args.get(3) {
+                Some(days) => days
+                    .parse::<i64>()
+                    .unwrap_or_else(|_| usage("snooze [ID] [DAYS]")),
+                None => 1,
+            };
+            let due_date = Todo::snooze(&conn, id, days)?;
+            println!("Task {} is due on {}", id, due_date);
+            Ok(())
+        }
+        "export" => {
+            match flag_value(&args, "--format") {
+                Some("ics") => print!("{}", Todo::export_ical(&conn)?),
+                Some("todotxt") => print!("{}", Todo::export_todotxt(&conn)?),
+                Some("csv") => print!("{}", Todo::export_csv(&conn)?),
+                Some("json") => print!("{}", Todo::export_json(&conn)?),
+                Some(format) => {
+                    eprintln!("Unknown export format '{}'", format);
+                    std::process::exit(EXIT_USAGE);
                 }
-                Err(e) => {
-                    eprintln!("{}", e)
+                None => {
+                    usage("export --format [ics|todotxt|csv|json]");
                 }
             }
             Ok(())
         }
-        "rm" => {
+        "import" => {
             if args.len() < 3 {
-                help()?;
-                std::process::exit(1);
+                usage("import [FILE]");
+            }
+            match fs::read_to_string(&args[2]) {
+                Ok(input) => {
+                    let count = Todo::import_todotxt(&conn, &input)?;
+                    println!("Imported {} tasks from {}", count, args[2]);
+                }
+                Err(e) => {
+                    eprintln!("Could not read {}: {}", args[2], e);
+                    std::process::exit(EXIT_NOT_FOUND);
+                }
+            }
+            Ok(())
+        }
+        "clear" => {
+            if dry_run {
+                return print_dry_run(Todo::list_filtered(&conn, Some(true))?, glyphs);
+            }
+            let deleted = Todo::clear_completed(&conn)?;
+            println!("Removed {} completed tasks", deleted);
+            Ok(())
+        }
+        "tui" => {
+            run_tui(&conn);
+            Ok(())
+        }
+        "reset" => {
+            if dry_run {
+                return print_dry_run(Todo::list(&conn, false)?, glyphs);
+            }
+            let assume_yes = args.iter().any(|arg| arg == "--yes" || arg == "-y");
+            // Nobody can answer when stdin is piped or the question can't be
+            // seen, so scripts have to pass --yes rather than get a silent no
+            let can_ask = io::stdin().is_terminal() && user_attended_stderr();
+            if !(assume_yes || can_ask) {
+                eprintln!(
+                    "{}",
+                    style("No terminal to confirm the reset on, pass --yes to reset anyway").red()
+                );
+                std::process::exit(EXIT_USAGE);
+            }
+            let prompt = style("Do you REALLY want to reset?").bright().red();
+            let confirmed = assume_yes
+                || confirm(
+                    &mut io::stdin().lock(),
+                    &mut Term::stderr(),
+                    &prompt.to_string(),
+                )
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    false
+                });
+            if confirmed {
+                Todo::reset(&conn)?;
+                println!("Database reset. All tasks cleared.");
             } else {
-                let id = args[2].parse::<i32>().unwrap();
-                Todo::rm(&conn, id)?;
+                println!("Alright. No reset!");
+            }
+            Ok(())
+        }
+        "rm" => {
+            let ids = without_flags(&args[2..], &["--dry-run"], &[])
+                .into_iter()
+                .map(|arg| resolve_id(&conn, arg))
+                .collect::<Result<Vec<i32>>>()?;
+            if ids.is_empty() {
+                usage("rm [ID]...");
+            }
+            // Checks every id first so that nothing is removed if one is wrong
+            let mut targets: Vec<Todo> = Vec::new();
+            for &id in &ids {
+                for todo in Todo::subtree(&conn, id)? {
+                    if !targets.iter().any(|target| target.id == todo.id) {
+                        targets.push(todo);
+                    }
+                }
+            }
+            if dry_run {
+                return print_dry_run(targets, glyphs);
+            }
+            for id in ids {
+                // Already gone if it was a subtask of an earlier id
+                if Todo::get(&conn, id).is_ok() {
+                    Todo::rm(&conn, id)?;
+                }
                 println!("Removed task with ID: {}", id);
             }
             Ok(())
         }
+        "backup" => {
+            let dir = args.get(2).map_or(".", |dir| dir.as_str());
+            let backup_path = backup(&conn, Path::new(dir))?;
+            println!("Database backed up to {}", backup_path.display());
+            Ok(())
+        }
+        "vacuum" | "compact" => {
+            let (before, after) = vacuum(&conn)?;
+            println!("Database compacted from {} to {} bytes", before, after);
+            Ok(())
+        }
         "sort" => {
             println!("TODO List (sorted by status):");
             let todos = Todo::list(&conn, true)?;
-            Todo::print_list(todos)?;
+            Todo::print_list(todos, glyphs)?;
             Ok(())
         }
-        "help" | "--help" | "-h" | _ => help(),
+        "help" | "--help" | "-h" => help(),
+        _ => {
+            eprintln!("{}", style(format!("Unknown command '{}'", command)).red());
+            help()?;
+            std::process::exit(EXIT_USAGE);
+        }
     }?;
     Ok(())
 }
+
+// Parses a task id, exiting with an error if it isn't a number
+fn parse_id(arg: &str) -> i32 {
+    match arg.parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => {
+            eprintln!("'{}' is not a valid task id", arg);
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+}
+
+// Reports on the database without changing it: its schema version, how many
+// tasks it has and whether sqlite finds it corrupted. Exits with EXIT_DB when
+// something needs fixing.
+fn doctor(db_path: &Path) -> Result<()> {
+    println!("Database: {}", db_path.display());
+    if db_path == Path::new(MEMORY_DB_PATH) || !db_path.exists() {
+        println!("Tasks: none, the database will be created when a task is added");
+        println!("{}", style("OK").green());
+        return Ok(());
+    }
+
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut issues = Vec::new();
+    let readable = match migrations::schema_version(&conn) {
+        Ok(version) => {
+            println!(
+                "Schema version: {} (this todo writes {})",
+                version,
+                migrations::SCHEMA_VERSION
+            );
+            if version > migrations::SCHEMA_VERSION {
+                issues.push("the database was written by a newer todo, upgrade todo".to_string());
+            } else if version < migrations::SCHEMA_VERSION {
+                issues.push(
+                    "the schema is out of date, any other command will upgrade it".to_string(),
+                );
+            }
+            true
+        }
+        Err(error) => {
+            issues.push(format!("could not read the database ({})", error));
+            false
+        }
+    };
+    // The other checks would fail the same way on a file sqlite can't read
+    if readable {
+        match Todo::stats(&conn) {
+            Ok(stats) => println!(
+                "Tasks: {} ({} pending, {} done)",
+                stats.total, stats.pending, stats.done
+            ),
+            Err(error) => issues.push(format!("could not count the tasks ({})", error)),
+        }
+        match Todo::integrity_check(&conn) {
+            Ok(true) => {}
+            Ok(false) => {
+                issues.push("the integrity check found corruption, restore a backup".to_string())
+            }
+            Err(error) => issues.push(format!("could not check the integrity ({})", error)),
+        }
+    }
+
+    if issues.is_empty() {
+        println!("{}", style("OK").green());
+        Ok(())
+    } else {
+        println!("{} {}", style("ISSUES:").red(), issues.join("; "));
+        std::process::exit(EXIT_DB);
+    }
+}
+
+// Shows a few sample tasks from a store that lives in memory
+fn demo(glyphs: StatusGlyphs) -> Result<()> {
+    let store = InMemoryStore::new();
+    for name in ["Try out todo", "Add a task", "Toggle it when it's done"] {
+        store.add(name)?;
+    }
+    store.toggle(1)?;
+    println!("TODO List (demo, nothing is saved):");
+    Todo::print_list(store.list(None)?, glyphs)
+}
+
+// Turns an id or the start of a task name into an id, exiting with an error
+// when the name matches no task or several of them. A number is taken as an
+// id unless no task has it, so "2024" can still find "2024 taxes".
+fn resolve_id(conn: &Connection, arg: &str) -> Result<i32> {
+    let number = arg.parse::<i32>().ok();
+    if let Some(id) = number {
+        match Todo::get(conn, id) {
+            Err(TodoError::NotFound(_)) => {}
+            result => return result.map(|_| id),
+        }
+    }
+    match Todo::resolve(conn, arg)? {
+        Resolution::Unique(id) => Ok(id),
+        Resolution::Ambiguous(ids) => {
+            eprintln!("'{}' matches several tasks, use one of their ids:", arg);
+            for id in ids {
+                eprintln!("{:>4} | {}", id, Todo::get(conn, id)?.name);
+            }
+            std::process::exit(EXIT_USAGE);
+        }
+        // The command reports a number that matches nothing as a missing id
+        Resolution::None => match number {
+            Some(id) => Ok(id),
+            None => {
+                eprintln!(
+                    "'{}' is not a valid task id or the start of a task name",
+                    arg
+                );
+                std::process::exit(EXIT_NOT_FOUND);
+            }
+        },
+    }
+}
+
+// Opens the interactive list, exiting with an error if it fails
+#[cfg(feature = "tui")]
+fn run_tui(conn: &Connection) {
+    if let Err(e) = todo::tui::run(conn) {
+        eprintln!("{}", e);
+        std::process::exit(EXIT_DB);
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_tui(_conn: &Connection) {
+    eprintln!("This build doesn't include the TUI, rebuild it with --features tui");
+    std::process::exit(EXIT_USAGE);
+}
+
+// Shows which tasks a destructive command would delete, without deleting them
+fn print_dry_run(todos: Vec<Todo>, glyphs: StatusGlyphs) -> Result<()> {
+    println!("Dry run, {} tasks would be removed:", todos.len());
+    Todo::print_list(todos, glyphs)
+}
+
+// Prints how a command should be used and exits with an error
+fn usage(command: &str) -> ! {
+    eprintln!("Usage: todo {}", command);
+    eprintln!("Run 'todo help' to see all commands.");
+    std::process::exit(EXIT_USAGE);
+}
+
+// Drops the given flags from the arguments, along with the values of the
+// `value_flags`, and exits with a usage error on any other "--flag"
+fn without_flags<'a>(args: &'a [String], flags: &[&str], value_flags: &[&str]) -> Vec<&'a str> {
+    let mut kept = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if value_flags.contains(&arg.as_str()) {
+            args.next();
+        } else if arg.starts_with("--") && !flags.contains(&arg.as_str()) {
+            eprintln!("Unknown option {}", arg);
+            eprintln!("Run 'todo help' to see all commands.");
+            std::process::exit(EXIT_USAGE);
+        } else if !flags.contains(&arg.as_str()) {
+            kept.push(arg.as_str());
+        }
+    }
+    kept
+}
+
+// Removes a flag (under any of its names) from the arguments, returning
+// whether it was there
+fn take_flag(args: &mut Vec<String>, names: &[&str]) -> bool {
+    let count = args.len();
+    args.retain(|arg| !names.contains(&arg.as_str()));
+    args.len() < count
+}
+
+// Removes a flag and its value from the arguments, returning the value
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let position = args.iter().position(|arg| arg == flag)?;
+    args.remove(position);
+    if position < args.len() {
+        Some(args.remove(position))
+    } else {
+        None
+    }
+}
+
+// Parses the numeric value of a flag, exiting with an error if it isn't one
+fn usize_flag(args: &[String], flag: &str) -> Option<usize> {
+    let value = flag_value(args, flag)?;
+    match value.parse::<usize>() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            eprintln!("'{}' is not a valid value for {}", value, flag);
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+}
+
+// Returns the value following a flag, e.g. "ics" for "--format ics"
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let position = args.iter().position(|arg| arg == flag)?;
+    args.get(position + 1).map(|value| value.as_str())
+}
@@ -0,0 +1,284 @@
+use crate::{Result, TodoError};
+use console::style;
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+
+// A step that brings the schema from one version to the next. Steps only
+// change what isn't there yet, so running one on a database that already
+// has it (e.g. one created before versions were tracked) is harmless.
+type Migration = fn(&Transaction) -> Result<()>;
+
+// Every schema change, in order. A database at version N has had the first
+// N applied. Add new steps at the end and never change the existing ones.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("create the todo table", create_todo_table),
+    (
+        "add subtasks, due dates, priorities and tags",
+        add_task_details,
+    ),
+    ("index the status and creation date", add_indexes),
+    ("store creation dates as UTC text", normalize_dates),
+    ("add named lists", add_list),
+    ("add color labels", add_color),
+];
+
+// The schema version this build of todo writes
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+// Reads the schema version, which sqlite keeps in the file's user_version
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+// Applies the migrations the database doesn't have yet, each one in its own
+// transaction along with the version bump. Upgrades of an existing database
+// are logged to stderr. Refuses databases written by a newer todo, which
+// could have data this version would lose.
+pub fn migrate(conn: &Connection) -> Result<()> {
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(TodoError::SchemaTooNew(version, SCHEMA_VERSION));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+    let is_new = version == 0 && !table_exists(conn, "todo")?;
+
+    for (index, (description, migration)) in MIGRATIONS.iter().enumerate() {
+        let target = index as u32 + 1;
+        // Taking the write lock first means another process migrating the
+        // same file at the same time waits, then sees the steps it made
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        if schema_version(&tx)? >= target {
+            continue;
+        }
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", target)?;
+        tx.commit()?;
+        if !is_new {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "Upgraded the database to version {}: {}",
+                    target, description
+                ))
+                .dim()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+        [table],
+        |row| row.get(0),
+    )?)
+}
+
+// The table as the first versions of todo created it
+fn create_todo_table(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS todo (
+    	id	        INTEGER NOT NULL,
+    	name	    TEXT NOT NULL,
+    	date_added	REAL NOT NULL DEFAULT current_timestamp,
+    	is_done	    NUMERIC NOT NULL DEFAULT 0,
+    	    PRIMARY KEY(id AUTOINCREMENT)
+    )",
+        [], // no params for this query
+    )?;
+    Ok(())
+}
+
+fn add_task_details(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(
+        tx,
+        "parent_id",
+        "INTEGER REFERENCES todo(id) ON DELETE CASCADE",
+    )?;
+    add_column_if_missing(tx, "due_date", "TEXT")?;
+    add_column_if_missing(tx, "priority", "TEXT")?;
+    add_column_if_missing(tx, "tags", "TEXT")
+}
+
+// Used by the status sorted listing and the date filters
+fn add_indexes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE INDEX IF NOT EXISTS todo_is_done ON todo (is_done, id);
+        CREATE INDEX IF NOT EXISTS todo_date_added ON todo (date_added);",
+    )?;
+    Ok(())
+}
+
+// Rewrites dates that older versions stored in other formats (julian day
+// numbers, RFC 3339 text, fractional seconds) as UTC "YYYY-MM-DD HH:MM:SS",
+// so they sort and compare correctly
+fn normalize_dates(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "UPDATE todo SET date_added = datetime(date_added)
+            WHERE date_added IS NOT datetime(date_added) AND datetime(date_added) IS NOT NULL",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_list(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "list", "TEXT")
+}
+
+fn add_color(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "color", "TEXT")
+}
+
+// Aux function that adds a column to the todo table if it's not there yet
+fn add_column_if_missing(conn: &Connection, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM pragma_table_info('todo') WHERE name = ?")?;
+    let count: i32 = stmt.query_row([column], |row| row.get(0))?;
+    if count == 0 {
+        conn.execute(
+            &format!("ALTER TABLE todo ADD COLUMN {} {}", column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/* -----------------------------------------------------------
+ *    Tests
+ * ---------------------------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_connection_at, Todo};
+
+    // A database as todo 0.1 left it: no version, four columns, and dates
+    // in whatever format they were written
+    fn v0_database(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE todo (
+                id	        INTEGER NOT NULL,
+                name	    TEXT NOT NULL,
+                date_added	REAL NOT NULL DEFAULT current_timestamp,
+                is_done	    NUMERIC NOT NULL DEFAULT 0,
+                    PRIMARY KEY(id AUTOINCREMENT)
+            );
+            INSERT INTO todo (name, date_added, is_done) VALUES
+                ('Buy milk', '2023-11-02 10:00:00', 0),
+                ('Pay rent', '2023-11-03T09:30:00+01:00', 1),
+                ('Call mum', 2460251.5, 0),
+                ('Deleted', '2023-11-04 12:00:00', 0);
+            DELETE FROM todo WHERE id = 4;",
+        )
+        .expect("Could not create the v0 database");
+    }
+
+    #[test]
+    fn test_migrates_v0_database() {
+        let db_folder = tempfile::tempdir().expect("Could not create a temp dir");
+        let db_path = db_folder.path().join("todo.sqlite");
+        v0_database(&Connection::open(&db_path).expect("Could not open the db"));
+
+        let conn = get_connection_at(&db_path).expect("Could not migrate the db");
+        assert_eq!(schema_version(&conn).expect("No version"), SCHEMA_VERSION);
+
+        let todos = Todo::list(&conn, false).expect("Failed to list todo");
+        let rows: Vec<(i32, &str, String, u8)> = todos
+            .iter()
+            .map(|todo| {
+                (
+                    todo.id,
+                    todo.name.as_str(),
+                    todo.date_added.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    todo.is_done,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (1, "Buy milk", "2023-11-02 10:00:00".to_string(), 0),
+                (2, "Pay rent", "2023-11-03 08:30:00".to_string(), 1),
+                (3, "Call mum", "2023-11-03 00:00:00".to_string(), 0),
+            ]
+        );
+
+        let stored: Vec<String> = conn
+            .prepare("SELECT date_added FROM todo ORDER BY id")
+            .expect("Failed to prepare statement")
+            .query_map([], |row| row.get(0))
+            .expect("Failed to query dates")
+            .collect::<rusqlite::Result<_>>()
+            .expect("Dates are not stored as text");
+        assert_eq!(
+            stored,
+            [
+                "2023-11-02 10:00:00",
+                "2023-11-03 08:30:00",
+                "2023-11-03 00:00:00"
+            ]
+        );
+
+        // New columns work, and the id sequence carries on
+        let child = Todo::add_subtask(&conn, 1, "Find a shop").expect("Could not add subtask");
+        assert_eq!(child.id, 5);
+        Todo::set_due(&conn, 1, "2023-12-01").expect("Could not set due date");
+        let indexes: i32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name LIKE 'todo_%'",
+                [],
+                |row| row.get(0),
+            )
+            .expect("Could not count indexes");
+        assert_eq!(indexes, 2);
+
+        // Opening it again has nothing left to do
+        drop(conn);
+        let conn = get_connection_at(&db_path).expect("Could not reopen the db");
+        assert_eq!(Todo::list(&conn, false).expect("Failed to list").len(), 4);
+    }
+
+    #[test]
+    fn test_new_database_is_at_latest_version() {
+        let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        migrate(&conn).expect("Could not create the schema");
+        assert_eq!(schema_version(&conn).expect("No version"), SCHEMA_VERSION);
+
+        // Databases made before versions were tracked already have every
+        // column, so running the steps again on them changes nothing
+        conn.pragma_update(None, "user_version", 0)
+            .expect("Could not reset the version");
+        migrate(&conn).expect("Could not migrate again");
+        assert_eq!(schema_version(&conn).expect("No version"), SCHEMA_VERSION);
+
+        // The column keeps the REAL type of the first versions, but the dates
+        // todo writes aren't numbers, so they're stored as text
+        let todo = Todo::add(&conn, "Buy milk").expect("Could not add todo");
+        let stored: String = conn
+            .query_row(
+                "SELECT typeof(date_added) FROM todo WHERE id = ?",
+                [todo.id],
+                |row| row.get(0),
+            )
+            .expect("Could not read the date");
+        assert_eq!(stored, "text");
+    }
+
+    #[test]
+    fn test_refuses_newer_database() {
+        let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        migrate(&conn).expect("Could not create the schema");
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .expect("Could not set the version");
+
+        let error = migrate(&conn).expect_err("A newer database was accepted");
+        assert!(matches!(error, TodoError::SchemaTooNew(found, supported)
+            if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION));
+        assert_eq!(
+            schema_version(&conn).expect("No version"),
+            SCHEMA_VERSION + 1,
+            "The version was changed"
+        );
+    }
+}
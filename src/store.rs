@@ -0,0 +1,282 @@
+use crate::{validate_name, Result, Todo, TodoError};
+use chrono::{SubsecRound, Utc};
+use rusqlite::Connection;
+use std::sync::{Mutex, MutexGuard, RwLock};
+
+// The operations every way of storing todos supports. Object safe, so that
+// callers can hold an `Arc<dyn TodoStore + Send + Sync>`.
+pub trait TodoStore {
+    // Adds a task, which gets the next id, and returns it
+    fn add(&self, name: &str) -> Result<Todo>;
+    // Lists the tasks in id order, only the done (true) or pending (false)
+    // ones when a status is given
+    fn list(&self, status: Option<bool>) -> Result<Vec<Todo>>;
+    // Flips a task between done and pending, returning true when it's now done
+    fn toggle(&self, id: i32) -> Result<bool>;
+    // Removes a task and its subtasks
+    fn rm(&self, id: i32) -> Result<()>;
+    // Removes every task
+    fn reset(&self) -> Result<()>;
+}
+
+// Stores todos in sqlite through the Todo methods
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    // Wraps a connection whose tables were already created with verify_db
+    pub fn new(conn: Connection) -> Self {
+        SqliteStore {
+            conn: Mutex::new(conn),
+        }
+    }
+
+    // A panic while holding the lock can't leave a Connection half updated,
+    // so a poisoned lock is still safe to use
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl TodoStore for SqliteStore {
+    fn add(&self, name: &str) -> Result<Todo> {
+        Todo::add(&self.conn(), name)
+    }
+
+    fn list(&self, status: Option<bool>) -> Result<Vec<Todo>> {
+        Todo::list_filtered(&self.conn(), status)
+    }
+
+    fn toggle(&self, id: i32) -> Result<bool> {
+        Todo::toggle(&self.conn(), id)
+    }
+
+    fn rm(&self, id: i32) -> Result<()> {
+        Todo::rm(&self.conn(), id)
+    }
+
+    fn reset(&self) -> Result<()> {
+        Todo::reset(&self.conn())
+    }
+}
+
+// Keeps todos in memory, following the same rules as sqlite: ids keep
+// growing even after a reset, and tasks are listed in insertion order
+#[derive(Default)]
+pub struct InMemoryStore {
+    state: RwLock<InMemoryState>,
+}
+
+#[derive(Default)]
+struct InMemoryState {
+    todos: Vec<Todo>,
+    last_id: i32,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        InMemoryStore::default()
+    }
+}
+
+impl TodoStore for InMemoryStore {
+    fn add(&self, name: &str) -> Result<Todo> {
+        let name = validate_name(name)?;
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.last_id += 1;
+        let todo = Todo::new(
+            state.last_id,
+            name.to_string(),
+            Utc::now().trunc_subsecs(0),
+            0,
+        );
+        state.todos.push(todo.clone());
+        Ok(todo)
+    }
+
+    fn list(&self, status: Option<bool>) -> Result<Vec<Todo>> {
+        let state = self
+            .state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Ok(state
+            .todos
+            .iter()
+            .filter(|todo| status.is_none_or(|done| (todo.is_done == 1) == done))
+            .cloned()
+            .collect())
+    }
+
+    fn toggle(&self, id: i32) -> Result<bool> {
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let todo = state
+            .todos
+            .iter_mut()
+            .find(|todo| todo.id == id)
+            .ok_or(TodoError::NotFound(id))?;
+        todo.is_done = 1 - todo.is_done;
+        Ok(todo.is_done == 1)
+    }
+
+    fn rm(&self, id: i32) -> Result<()> {
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count = state.todos.len();
+        // Subtasks can't be added through the trait, so there are none to cascade to
+        state.todos.retain(|todo| todo.id != id);
+        if state.todos.len() == count {
+            return Err(TodoError::NotFound(id));
+        }
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<()> {
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.todos.clear();
+        Ok(())
+    }
+}
+
+/* -----------------------------------------------------------
+ *    Tests
+ * ---------------------------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify_db, TodoError};
+    use std::sync::Arc;
+
+    fn sqlite_store() -> SqliteStore {
+        let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        verify_db(&conn).expect("Cannot create tables");
+        SqliteStore::new(conn)
+    }
+
+    #[test]
+    fn test_sqlite_store_operations() {
+        check_store_operations(Arc::new(sqlite_store()));
+    }
+
+    #[test]
+    fn test_in_memory_store_operations() {
+        check_store_operations(Arc::new(InMemoryStore::new()));
+    }
+
+    fn check_store_operations(store: Arc<dyn TodoStore + Send + Sync>) {
+        store.add("Task 1").expect("Could not add todo");
+        store.add("Task 2").expect("Could not add todo");
+        let todos = store.list(None).expect("Could not list todos");
+        assert_eq!(todos.len(), 2);
+
+        store.toggle(todos[0].id).expect("Could not toggle todo");
+        let done = store.list(Some(true)).expect("Could not list todos");
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].name, "Task 1");
+
+        store.rm(todos[1].id).expect("Could not remove todo");
+        assert!(matches!(store.rm(todos[1].id), Err(TodoError::NotFound(_))));
+        assert_eq!(
+            store.list(Some(false)).expect("Could not list todos").len(),
+            0
+        );
+
+        store.reset().expect("Could not reset");
+        assert!(store.list(None).expect("Could not list todos").is_empty());
+    }
+
+    // What a store returned, without the timestamps that differ between runs
+    fn outcome<T>(result: Result<T>, store: &dyn TodoStore) -> (String, Vec<(i32, String, u8)>) {
+        let result = match result {
+            Ok(_) => "ok".to_string(),
+            Err(TodoError::NotFound(id)) => format!("not found {}", id),
+            Err(TodoError::Validation(_)) => "invalid".to_string(),
+            Err(error) => panic!("Unexpected error: {}", error),
+        };
+        let todos = store
+            .list(None)
+            .expect("Could not list todos")
+            .into_iter()
+            .map(|todo| (todo.id, todo.name, todo.is_done))
+            .collect();
+        (result, todos)
+    }
+
+    #[test]
+    fn test_in_memory_store_matches_sqlite() {
+        // A small xorshift generator keeps the sequences random but repeatable
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        };
+
+        for _ in 0..50 {
+            let sqlite = sqlite_store();
+            let memory = InMemoryStore::new();
+            for step in 0..40 {
+                // Ids slightly past the last one also exercise missing ids
+                let id = next(12) as i32;
+                let (sqlite_outcome, memory_outcome) = match next(10) {
+                    0..=3 => {
+                        let name = if next(8) == 0 {
+                            " ".to_string()
+                        } else {
+                            format!("Task {}", step)
+                        };
+                        (
+                            outcome(sqlite.add(&name), &sqlite),
+                            outcome(memory.add(&name), &memory),
+                        )
+                    }
+                    4..=5 => (
+                        outcome(sqlite.toggle(id), &sqlite),
+                        outcome(memory.toggle(id), &memory),
+                    ),
+                    6..=7 => (
+                        outcome(sqlite.rm(id), &sqlite),
+                        outcome(memory.rm(id), &memory),
+                    ),
+                    8 => (
+                        outcome(sqlite.reset(), &sqlite),
+                        outcome(memory.reset(), &memory),
+                    ),
+                    _ => {
+                        let status = Some(next(2) == 0);
+                        let ids = |store: &dyn TodoStore| -> Vec<i32> {
+                            store
+                                .list(status)
+                                .expect("Could not list todos")
+                                .iter()
+                                .map(|todo| todo.id)
+                                .collect()
+                        };
+                        assert_eq!(ids(&sqlite), ids(&memory), "Filtered lists differ");
+                        continue;
+                    }
+                };
+                assert_eq!(
+                    sqlite_outcome, memory_outcome,
+                    "Stores diverged at step {}",
+                    step
+                );
+            }
+        }
+    }
+}
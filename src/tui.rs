@@ -0,0 +1,189 @@
+use crate::{Result, Todo};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use rusqlite::Connection;
+use std::error::Error;
+
+const HELP: &str = "↑/↓ move · space toggle · a add · d delete · q quit";
+
+// State of the interactive list
+struct App {
+    todos: Vec<Todo>,
+    state: ListState,
+    // Name being typed while adding a task
+    input: Option<String>,
+    message: Option<String>,
+    should_quit: bool,
+}
+
+impl App {
+    fn new(conn: &Connection) -> Result<Self> {
+        let mut app = App {
+            todos: Vec::new(),
+            state: ListState::default(),
+            input: None,
+            message: None,
+            should_quit: false,
+        };
+        app.reload(conn)?;
+        Ok(app)
+    }
+
+    // Reads the tasks again after a change, keeping the selection in range
+    fn reload(&mut self, conn: &Connection) -> Result<()> {
+        self.todos = Todo::list(conn, false)?;
+        let selected = match self.state.selected() {
+            _ if self.todos.is_empty() => None,
+            Some(index) => Some(index.min(self.todos.len() - 1)),
+            None => Some(0),
+        };
+        self.state.select(selected);
+        Ok(())
+    }
+
+    fn selected_id(&self) -> Option<i32> {
+        self.state
+            .selected()
+            .and_then(|index| self.todos.get(index))
+            .map(|todo| todo.id)
+    }
+
+    // Applies a key press, running the matching Todo method
+    fn handle_key(&mut self, conn: &Connection, key: KeyCode) -> Result<()> {
+        if let Some(input) = self.input.as_mut() {
+            match key {
+                KeyCode::Enter => {
+                    let name = input.clone();
+                    self.input = None;
+                    self.message = Todo::add(conn, &name).err().map(|e| e.to_string());
+                    self.reload(conn)?;
+                    self.state.select(self.todos.len().checked_sub(1));
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        self.message = None;
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => self.should_quit = true,
+            KeyCode::Down | KeyCode::Char('j') => self.state.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.state.select_previous(),
+            KeyCode::Char(' ') => {
+                if let Some(id) = self.selected_id() {
+                    Todo::toggle(conn, id)?;
+                    self.reload(conn)?;
+                }
+            }
+            KeyCode::Char('d') => {
+                if let Some(id) = self.selected_id() {
+                    Todo::rm(conn, id)?;
+                    self.reload(conn)?;
+                }
+            }
+            KeyCode::Char('a') => self.input = Some(String::new()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, footer_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+
+        let items: Vec<ListItem> = self
+            .todos
+            .iter()
+            .map(|todo| {
+                let (mark, color) = if todo.is_done == 1 {
+                    ("[x]", Color::Green)
+                } else {
+                    ("[ ]", Color::Red)
+                };
+                ListItem::new(format!("{} {:>4}  {}", mark, todo.id, todo.name))
+                    .style(Style::default().fg(color))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(" TODO List "))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.state);
+
+        let footer = match (&self.input, &self.message) {
+            (Some(input), _) => format!("New task: {}_", input),
+            (None, Some(message)) => message.clone(),
+            (None, None) => HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(footer), footer_area);
+    }
+
+    fn run(
+        &mut self,
+        conn: &Connection,
+        terminal: &mut DefaultTerminal,
+    ) -> Result<(), Box<dyn Error>> {
+        while !self.should_quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.handle_key(conn, key.code)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs the interactive terminal UI until the user quits
+pub fn run(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let mut app = App::new(conn)?;
+    let mut terminal = ratatui::try_init()?;
+    let result = app.run(conn, &mut terminal);
+    ratatui::restore();
+    result
+}
+
+/* -----------------------------------------------------------
+ *    Tests
+ * ---------------------------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify_db;
+
+    #[test]
+    fn test_tui_keys_call_todo_methods() {
+        let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        verify_db(&conn).expect("Cannot create tables");
+        let mut app = App::new(&conn).expect("Could not start the app");
+
+        for key in [
+            KeyCode::Char('a'),
+            KeyCode::Char('T'),
+            KeyCode::Char('1'),
+            KeyCode::Enter,
+        ] {
+            app.handle_key(&conn, key).expect("Key press failed");
+        }
+        assert_eq!(app.todos.len(), 1, "'a' did not add a task");
+        assert_eq!(app.todos[0].name, "T1");
+
+        app.handle_key(&conn, KeyCode::Char(' '))
+            .expect("Key press failed");
+        assert_eq!(app.todos[0].is_done, 1, "Space did not toggle the task");
+
+        app.handle_key(&conn, KeyCode::Char('d'))
+            .expect("Key press failed");
+        assert!(app.todos.is_empty(), "'d' did not delete the task");
+        assert_eq!(app.state.selected(), None);
+    }
+}
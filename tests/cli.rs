@@ -0,0 +1,414 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+// Runs the todo binary against a database inside the given folder
+fn run_todo(db_folder: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_todo"))
+        .args(args)
+        .env("TODO_DB_PATH", db_folder.join("todo.sqlite"))
+        .output()
+        .expect("Failed to run the todo binary")
+}
+
+#[test]
+fn test_unknown_command() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let output = run_todo(db_folder.path(), &["frobnicate"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success(), "Unknown command exited with 0");
+    assert!(
+        stderr.contains("Unknown command 'frobnicate'"),
+        "Unknown command was not reported: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_missing_id_argument() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+
+    for command in ["toggle", "rm"] {
+        let output = run_todo(db_folder.path(), &[command]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert!(
+            !output.status.success(),
+            "{} without an id exited with 0",
+            command
+        );
+        assert!(
+            stderr.contains(&format!("Usage: todo {} [ID]", command)),
+            "No usage error for {}: {}",
+            command,
+            stderr
+        );
+        assert!(!stderr.contains("panicked"), "{} panicked", command);
+    }
+}
+
+#[test]
+fn test_non_numeric_id() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let output = run_todo(db_folder.path(), &["rm", "abc"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success(), "rm with a bad id exited with 0");
+    assert!(
+        stderr.contains("'abc' is not a valid task id"),
+        "Bad id was not reported: {}",
+        stderr
+    );
+    assert!(!stderr.contains("panicked"), "rm panicked on a bad id");
+}
+
+#[test]
+fn test_missing_task_id() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let output = run_todo(db_folder.path(), &["toggle", "9999"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(
+        !output.status.success(),
+        "toggle of a missing id exited with 0"
+    );
+    assert!(
+        stderr.contains("No task with id 9999"),
+        "Missing id was not reported: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_dry_run_changes_nothing() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    run_todo(db_folder.path(), &["add", "Keep me"]);
+    run_todo(db_folder.path(), &["add", "Keep me too"]);
+    run_todo(db_folder.path(), &["toggle", "2"]);
+
+    for args in [
+        &["reset", "--dry-run"][..],
+        &["clear", "--dry-run"],
+        &["rm", "1", "2", "--dry-run"],
+    ] {
+        let output = run_todo(db_folder.path(), args);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{:?} failed", args);
+        assert!(
+            stdout.contains("Keep me too"),
+            "{:?} did not list its targets: {}",
+            args,
+            stdout
+        );
+    }
+
+    let output = run_todo(db_folder.path(), &["list"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Keep me"), "A dry run deleted tasks");
+    assert!(stdout.contains("Keep me too"), "A dry run deleted tasks");
+}
+
+#[test]
+fn test_memory_db_creates_no_file() {
+    let work_dir = TempDir::new().expect("Could not create temp dir");
+
+    for (args, db_env) in [
+        (&["--db", ":memory:", "add", "Ephemeral"][..], ""),
+        (&["add", "Ephemeral"], ":memory:"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_todo"))
+            .args(args)
+            .env("TODO_DB_PATH", db_env)
+            .env("HOME", work_dir.path())
+            .current_dir(work_dir.path())
+            .output()
+            .expect("Failed to run the todo binary");
+        assert!(output.status.success(), "{:?} on :memory: failed", args);
+    }
+
+    let created: Vec<_> = std::fs::read_dir(work_dir.path())
+        .expect("Could not read temp dir")
+        .collect();
+    assert!(created.is_empty(), "Files were created: {:?}", created);
+}
+
+#[test]
+fn test_verbose_logs_sql_without_changing_output() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    run_todo(db_folder.path(), &["add", "Logged"]);
+
+    let quiet = run_todo(db_folder.path(), &["list"]);
+    let verbose = run_todo(db_folder.path(), &["list", "--verbose"]);
+    let short = run_todo(db_folder.path(), &["-v", "list"]);
+
+    assert!(verbose.status.success(), "list --verbose failed");
+    assert_eq!(quiet.stdout, verbose.stdout, "--verbose changed the output");
+    assert_eq!(quiet.stdout, short.stdout, "-v changed the output");
+    assert!(quiet.stderr.is_empty(), "SQL was logged without --verbose");
+    let stderr = String::from_utf8_lossy(&verbose.stderr);
+    assert!(stderr.contains("SELECT"), "No SQL was logged: {}", stderr);
+}
+
+#[test]
+fn test_exit_codes() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    run_todo(db_folder.path(), &["add", "Exists"]);
+
+    for (args, expected) in [
+        (&["list"][..], 0),
+        (&["toggle", "9999"], 1),
+        (&["rm", "no such task"], 1),
+        (&["toggle"], 2),
+        (&["due", "1", "someday"], 2),
+        (&["frobnicate"], 2),
+    ] {
+        let output = run_todo(db_folder.path(), args);
+        assert_eq!(
+            output.status.code(),
+            Some(expected),
+            "Wrong exit code for {:?}",
+            args
+        );
+    }
+}
+
+#[test]
+fn test_demo_creates_no_database() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let output = run_todo(db_folder.path(), &["demo"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "demo failed");
+    assert!(stdout.contains("Try out todo"), "No demo tasks: {}", stdout);
+    assert!(
+        !db_folder.path().join("todo.sqlite").exists(),
+        "demo created a database"
+    );
+}
+
+#[test]
+fn test_add_from_stdin() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_todo"))
+        .args(["add", "-"])
+        .env("TODO_DB_PATH", db_folder.path().join("todo.sqlite"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run the todo binary");
+    child
+        .stdin
+        .take()
+        .expect("No stdin")
+        .write_all(b"Buy milk\n\n# groceries\nBuy bread +home\nbuy MILK\n")
+        .expect("Could not write to stdin");
+    let output = child.wait_with_output().expect("todo did not finish");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "add - failed");
+    assert!(stdout.contains("Added 2 tasks"), "{}", stdout);
+
+    let output = run_todo(db_folder.path(), &["list", "--format", "plain"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 2, "{}", stdout);
+}
+
+#[test]
+fn test_doctor_reports_healthy_database() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    run_todo(db_folder.path(), &["add", "Check me"]);
+    let output = run_todo(db_folder.path(), &["doctor"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "doctor failed: {}", stdout);
+    assert!(stdout.contains("todo.sqlite"), "No path: {}", stdout);
+    assert!(
+        stdout.contains("Tasks: 1 (1 pending, 0 done)"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.trim_end().ends_with("OK"), "{}", stdout);
+}
+
+#[test]
+fn test_doctor_reports_schema_issues_without_migrating() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let db_path = db_folder.path().join("todo.sqlite");
+    run_todo(db_folder.path(), &["add", "Check me"]);
+    let set_version = |version: u32| {
+        rusqlite::Connection::open(&db_path)
+            .expect("Could not open the db")
+            .pragma_update(None, "user_version", version)
+            .expect("Could not set the version");
+    };
+
+    for (version, issue) in [
+        (todo::migrations::SCHEMA_VERSION + 1, "newer todo"),
+        (todo::migrations::SCHEMA_VERSION - 1, "out of date"),
+    ] {
+        set_version(version);
+        let output = run_todo(db_folder.path(), &["doctor"]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        assert_eq!(output.status.code(), Some(3), "{}", stdout);
+        assert!(
+            stdout.contains(&format!("Schema version: {} ", version)),
+            "{}",
+            stdout
+        );
+        assert!(
+            stdout.contains("Tasks: 1 (1 pending, 0 done)"),
+            "{}",
+            stdout
+        );
+        assert!(
+            stdout.contains("ISSUES:") && stdout.contains(issue),
+            "{}",
+            stdout
+        );
+        let version_after: u32 = rusqlite::Connection::open(&db_path)
+            .expect("Could not open the db")
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .expect("Could not read the version");
+        assert_eq!(version_after, version, "doctor changed the schema");
+    }
+}
+
+#[test]
+fn test_dates_are_read_in_local_time() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let run_in_new_york = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_todo"))
+            .args(args)
+            .env("TODO_DB_PATH", db_folder.path().join("todo.sqlite"))
+            .env("TZ", "America/New_York")
+            .output()
+            .expect("Failed to run the todo binary")
+    };
+
+    let output = run_in_new_york(&["add", "Old", "--at", "2023-11-02"]);
+    assert!(output.status.success(), "add --at failed: {:?}", output);
+
+    // Midnight in New York is 4am UTC, and the table shows it back in local time
+    let csv =
+        String::from_utf8_lossy(&run_in_new_york(&["list", "--format", "csv"]).stdout).to_string();
+    assert!(
+        csv.contains("2023-11-02 04:00:00"),
+        "Not stored as UTC: {}",
+        csv
+    );
+    let table = String::from_utf8_lossy(&run_in_new_york(&["list"]).stdout).to_string();
+    assert!(
+        table.contains("2023-11-02 00:00:00"),
+        "Not shown in local time: {}",
+        table
+    );
+
+    // The range is local too, so the task is on the 2nd and not the 1st
+    let on_the_2nd = run_in_new_york(&["list", "--since", "2023-11-02", "--until", "2023-11-02"]);
+    assert!(String::from_utf8_lossy(&on_the_2nd.stdout).contains("Old"));
+    let on_the_1st = run_in_new_york(&["list", "--since", "2023-11-01", "--until", "2023-11-01"]);
+    assert!(!String::from_utf8_lossy(&on_the_1st.stdout).contains("Old"));
+}
+
+#[test]
+fn test_data_folder_without_home() {
+    let state_folder = TempDir::new().expect("Could not create temp dir");
+    let output = Command::new(env!("CARGO_BIN_EXE_todo"))
+        .args(["add", "No home"])
+        .env_remove("HOME")
+        .env_remove("TODO_DB_PATH")
+        .env("XDG_STATE_HOME", state_folder.path())
+        .output()
+        .expect("Failed to run the todo binary");
+
+    assert!(
+        output.status.success(),
+        "add failed without HOME: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(state_folder
+        .path()
+        .join("todo")
+        .join("todo.sqlite")
+        .exists());
+}
+
+#[test]
+fn test_reset_without_terminal_needs_yes() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    run_todo(db_folder.path(), &["add", "Keep me"]);
+
+    let output = run_todo(db_folder.path(), &["reset"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("--yes"), "No hint about --yes: {}", stderr);
+    let stdout = String::from_utf8_lossy(&run_todo(db_folder.path(), &["list"]).stdout).to_string();
+    assert!(stdout.contains("Keep me"), "reset ran without confirmation");
+
+    let output = run_todo(db_folder.path(), &["reset", "--yes"]);
+    assert!(output.status.success(), "reset --yes failed");
+    let stdout = String::from_utf8_lossy(&run_todo(db_folder.path(), &["list"]).stdout).to_string();
+    assert!(!stdout.contains("Keep me"), "reset --yes kept the tasks");
+}
+
+#[test]
+fn test_bad_max_name_len_is_reported_when_forced() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    for args in [&["add", "Buy milk"][..], &["add", "Buy milk", "--force"]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_todo"))
+            .args(args)
+            .env("TODO_DB_PATH", db_folder.path().join("todo.sqlite"))
+            .env("TODO_MAX_NAME_LEN", "abc")
+            .output()
+            .expect("Failed to run the todo binary");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(output.status.code(), Some(2), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("TODO_MAX_NAME_LEN"), "{}", stderr);
+        assert!(
+            !stderr.contains("--force"),
+            "Forcing can't help: {}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_number_falls_back_to_a_name() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    run_todo(db_folder.path(), &["add", "Buy milk"]);
+    run_todo(db_folder.path(), &["add", "2024 taxes"]);
+
+    // 1 is an id, but no task has the id 2024
+    let output = run_todo(db_folder.path(), &["show", "1"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Buy milk"));
+    let output = run_todo(db_folder.path(), &["show", "2024"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "show 2024 failed");
+    assert!(stdout.contains("2024 taxes"), "{}", stdout);
+
+    let output = run_todo(db_folder.path(), &["show", "9999"]);
+    assert_eq!(output.status.code(), Some(1), "A missing id was found");
+}
+
+#[test]
+fn test_add_keeps_words_that_look_like_flags_out() {
+    let db_folder = TempDir::new().expect("Could not create temp dir");
+    let output = run_todo(db_folder.path(), &["add", "buy", "--2", "eggs"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("Unknown option --2"), "{}", stderr);
+
+    // Known flags are still taken out of the name
+    let output = run_todo(
+        db_folder.path(),
+        &["add", "buy", "--force", "eggs", "--at", "2024-01-01"],
+    );
+    assert!(output.status.success(), "add failed: {:?}", output);
+    let output = run_todo(db_folder.path(), &["list", "--format", "plain"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(stdout.contains("\tbuy eggs\t"), "{}", stdout);
+}